@@ -0,0 +1,604 @@
+use bevy_app::{App, CoreStage, Plugin};
+use bevy_asset::{AddAsset, AssetLoader, Assets, Handle, LoadContext, LoadedAsset};
+use bevy_ecs::prelude::*;
+use bevy_reflect::TypeUuid;
+use bevy_utils::{BoxedFuture, Uuid};
+use crate::plugin::{prepare_within_budget, run_within_budget, FrameBudget};
+use crate::prelude::*;
+use crate::task::TaskMacro;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+
+// Behaviours loaded from RON files. The file describes the shape of the tree, and
+// conditions, effects, operators and macros are looked up by name in the
+// BehaviourLibrary<C> the game fills in. e.g.
+//
+// (
+//     name: "Student",
+//     root: Selector(name: "BeStudent", tasks: [
+//         Primitive(name: "Eat", conditions: ["hungry"], effects: ["fed"], operator: "eat"),
+//         Macro("MoveRandomly"),
+//     ]),
+// )
+
+#[derive(Deserialize, Debug)]
+pub struct BehaviourDef {
+    pub name: String,
+    /// Step limit for backtracking decomposition, first match if missing
+    #[serde(default)]
+    pub backtracking: Option<usize>,
+    /// How many references deep decomposition can go
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Capacity of a plan cache shared by the agents running it, none if missing
+    #[serde(default)]
+    pub plan_cache: Option<usize>,
+    pub root: TaskDef,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum TaskDef {
+    Selector {
+        name: String,
+        #[serde(default)]
+        conditions: Vec<String>,
+        /// Context keys the conditions read, see BehaviourBuilder::depends_on
+        #[serde(default)]
+        depends_on: Vec<String>,
+        tasks: Vec<TaskDef>,
+    },
+    Sequence {
+        name: String,
+        #[serde(default)]
+        conditions: Vec<String>,
+        #[serde(default)]
+        depends_on: Vec<String>,
+        tasks: Vec<TaskDef>,
+    },
+    Primitive {
+        name: String,
+        #[serde(default)]
+        conditions: Vec<String>,
+        #[serde(default)]
+        depends_on: Vec<String>,
+        #[serde(default)]
+        effects: Vec<String>,
+        operator: String,
+    },
+    UtilitySelector {
+        name: String,
+        #[serde(default)]
+        conditions: Vec<String>,
+        #[serde(default)]
+        depends_on: Vec<String>,
+        #[serde(default)]
+        random_ties: bool,
+        tasks: Vec<TaskDef>,
+    },
+    RandomSelector {
+        name: String,
+        #[serde(default)]
+        conditions: Vec<String>,
+        #[serde(default)]
+        depends_on: Vec<String>,
+        tasks: Vec<TaskDef>,
+    },
+    Repeat {
+        name: String,
+        times: usize,
+        #[serde(default)]
+        conditions: Vec<String>,
+        #[serde(default)]
+        depends_on: Vec<String>,
+        tasks: Vec<TaskDef>,
+    },
+    Wait {
+        name: String,
+        seconds: f32,
+    },
+    /// A task in a utility selector, with the name of its score in the library
+    Scored(String, Box<TaskDef>),
+    /// A task in a random selector, with its weight
+    Weighted(f32, Box<TaskDef>),
+    Pause,
+    /// A selector or sequence elsewhere in the file, by name
+    Reference(String),
+    /// Filled per agent at runtime, see BehaviourBuilder::slot
+    Slot(String),
+    /// A TaskMacro registered in the library
+    Macro(String),
+}
+
+#[derive(Debug)]
+pub enum BehaviourLoadError {
+    Parse(String),
+    UnknownCondition(String),
+    UnknownEffect(String),
+    UnknownScore(String),
+    UnknownOperator(String),
+    UnknownMacro(String),
+    Invalid(BehaviourError),
+}
+
+impl fmt::Display for BehaviourLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BehaviourLoadError::*;
+        match self {
+            Parse(e) => write!(f, "couldn't parse behaviour: {}", e),
+            UnknownCondition(name) => write!(f, "no condition called '{}' in the library", name),
+            UnknownEffect(name) => write!(f, "no effect called '{}' in the library", name),
+            UnknownScore(name) => write!(f, "no score called '{}' in the library", name),
+            UnknownOperator(name) => write!(f, "no operator called '{}' in the library", name),
+            UnknownMacro(name) => write!(f, "no macro called '{}' in the library", name),
+            Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for BehaviourLoadError {}
+
+/// The named building blocks behaviour files can use. Cheap to clone - every clone
+/// shares the same library, so the asset loader sees whatever the game adds.
+pub struct BehaviourLibrary<C: Context> {
+    inner: Arc<RwLock<LibraryInner<C>>>,
+}
+
+struct LibraryInner<C: Context> {
+    conditions: HashMap<String, Arc<dyn Condition<C>>>,
+    effects: HashMap<String, (EffectType, Arc<dyn Effect<C>>)>,
+    scores: HashMap<String, Arc<dyn Score<C>>>,
+    operators: HashMap<String, Arc<dyn Operator<C>>>,
+    macros: HashMap<String, Box<dyn TaskMacro<C> + Send + Sync>>,
+}
+
+impl<C: Context> Default for BehaviourLibrary<C> {
+    fn default() -> Self {
+        BehaviourLibrary {
+            inner: Arc::new(RwLock::new(LibraryInner {
+                conditions: HashMap::default(),
+                effects: HashMap::default(),
+                scores: HashMap::default(),
+                operators: HashMap::default(),
+                macros: HashMap::default(),
+            })),
+        }
+    }
+}
+
+impl<C: Context> Clone for BehaviourLibrary<C> {
+    fn clone(&self) -> Self {
+        BehaviourLibrary {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C: Context> BehaviourLibrary<C> {
+    pub fn add_condition<K: Condition<C> + 'static>(&self, name: &str, condition: K) {
+        self.inner.write().unwrap().conditions.insert(name.to_owned(), Arc::new(condition));
+    }
+
+    pub fn add_effect<E: Effect<C> + 'static>(&self, name: &str, effect_type: EffectType, effect: E) {
+        self.inner.write().unwrap().effects.insert(name.to_owned(), (effect_type, Arc::new(effect)));
+    }
+
+    pub fn add_score<S: Score<C> + 'static>(&self, name: &str, score: S) {
+        self.inner.write().unwrap().scores.insert(name.to_owned(), Arc::new(score));
+    }
+
+    pub fn add_operator<O: Operator<C> + 'static>(&self, name: &str, operator: O) {
+        self.inner.write().unwrap().operators.insert(name.to_owned(), Arc::new(operator));
+    }
+
+    pub fn add_macro<M: TaskMacro<C> + Send + Sync + 'static>(&self, name: &str, task_macro: M) {
+        self.inner.write().unwrap().macros.insert(name.to_owned(), Box::new(task_macro));
+    }
+
+    pub fn build_from_str(&self, ron: &str) -> Result<Behaviour<C>, BehaviourLoadError> {
+        let def: BehaviourDef = ron::de::from_str(ron)
+            .map_err(|e| BehaviourLoadError::Parse(e.to_string()))?;
+        self.build(&def)
+    }
+
+    pub fn build(&self, def: &BehaviourDef) -> Result<Behaviour<C>, BehaviourLoadError> {
+        let inner = self.inner.read().unwrap();
+        let mut builder = BehaviourBuilder::new(&def.name);
+        if let Some(max_steps) = def.backtracking {
+            builder.backtracking(max_steps);
+        }
+        if let Some(max_depth) = def.max_depth {
+            builder.max_depth(max_depth);
+        }
+        if let Some(capacity) = def.plan_cache {
+            builder.plan_cache(capacity);
+        }
+        inner.add_task(&mut builder, &def.root, Odds::default())?;
+        builder.build().map_err(BehaviourLoadError::Invalid)
+    }
+}
+
+// what the Scored and Weighted wrapping a task gave it
+#[derive(Default, Clone, Copy)]
+struct Odds<'d> {
+    score: Option<&'d String>,
+    weight: Option<f32>,
+}
+
+impl<C: Context> LibraryInner<C> {
+    fn add_task(&self, builder: &mut BehaviourBuilder<C>, def: &TaskDef, odds: Odds) -> Result<(), BehaviourLoadError> {
+        match def {
+            TaskDef::Selector { name, conditions, depends_on, tasks }
+            | TaskDef::Sequence { name, conditions, depends_on, tasks }
+            | TaskDef::UtilitySelector { name, conditions, depends_on, tasks, .. }
+            | TaskDef::RandomSelector { name, conditions, depends_on, tasks }
+            | TaskDef::Repeat { name, conditions, depends_on, tasks, .. } => {
+                match def {
+                    TaskDef::Selector { .. } => builder.selector(name),
+                    TaskDef::Sequence { .. } => builder.sequence(name),
+                    TaskDef::RandomSelector { .. } => builder.random_selector(name),
+                    TaskDef::Repeat { times, .. } => builder.repeat(name, *times),
+                    _ => builder.utility_selector(name),
+                };
+                if let TaskDef::UtilitySelector { random_ties: true, .. } = def {
+                    builder.random_ties();
+                }
+                self.add_odds(builder, odds)?;
+                self.add_conditions(builder, conditions, depends_on)?;
+                for task in tasks.iter() {
+                    self.add_task(builder, task, Odds::default())?;
+                }
+                builder.end();
+            },
+            TaskDef::Scored(score, task) => {
+                self.add_task(builder, task, Odds { score: Some(score), ..odds })?;
+            },
+            TaskDef::Weighted(weight, task) => {
+                self.add_task(builder, task, Odds { weight: Some(*weight), ..odds })?;
+            },
+            TaskDef::Primitive { name, conditions, depends_on, effects, operator } => {
+                builder.primitive(name);
+                self.add_odds(builder, odds)?;
+                self.add_conditions(builder, conditions, depends_on)?;
+                for effect_name in effects.iter() {
+                    let (effect_type, effect) = self.effects.get(effect_name)
+                        .ok_or_else(|| BehaviourLoadError::UnknownEffect(effect_name.clone()))?;
+                    builder.effect(effect_name, *effect_type, Shared(effect.clone()));
+                }
+                let op = self.operators.get(operator)
+                    .ok_or_else(|| BehaviourLoadError::UnknownOperator(operator.clone()))?;
+                builder.do_action(operator, Shared(op.clone()));
+                builder.end();
+            },
+            TaskDef::Wait { name, seconds } => {
+                // not builder.wait(), which would close the task before it was scored or weighted
+                builder.primitive(name);
+                self.add_odds(builder, odds)?;
+                builder.do_action("wait", Wait { seconds: *seconds });
+                builder.end();
+            },
+            TaskDef::Pause => {
+                builder.pause();
+            },
+            TaskDef::Reference(name) => {
+                builder.reference(name);
+            },
+            TaskDef::Slot(name) => {
+                builder.slot(name);
+            },
+            TaskDef::Macro(name) => {
+                let task_macro = self.macros.get(name)
+                    .ok_or_else(|| BehaviourLoadError::UnknownMacro(name.clone()))?;
+                task_macro.build(builder);
+            },
+        }
+        Ok(())
+    }
+
+    fn add_odds(&self, builder: &mut BehaviourBuilder<C>, odds: Odds) -> Result<(), BehaviourLoadError> {
+        if let Some(name) = odds.score {
+            let score = self.scores.get(name)
+                .ok_or_else(|| BehaviourLoadError::UnknownScore(name.clone()))?;
+            builder.score(name, Shared(score.clone()));
+        }
+        if let Some(weight) = odds.weight {
+            builder.weight(weight);
+        }
+        Ok(())
+    }
+
+    fn add_conditions(&self, builder: &mut BehaviourBuilder<C>, names: &[String], depends_on: &[String]) -> Result<(), BehaviourLoadError> {
+        for name in names.iter() {
+            let condition = self.conditions.get(name)
+                .ok_or_else(|| BehaviourLoadError::UnknownCondition(name.clone()))?;
+            builder.condition(name, Shared(condition.clone()));
+        }
+        for key in depends_on.iter() {
+            builder.depends_on(KeyId::intern(key));
+        }
+        Ok(())
+    }
+}
+
+// lets one library entry be used by every task (and every behaviour) that names it
+struct Shared<T: ?Sized>(Arc<T>);
+
+impl<C: Context> Condition<C> for Shared<dyn Condition<C>> {
+    fn is_valid(&self, ctx: &C) -> bool {
+        self.0.is_valid(ctx)
+    }
+}
+
+impl<C: Context> Effect<C> for Shared<dyn Effect<C>> {
+    fn apply(&self, ctx: &mut C) {
+        self.0.apply(ctx)
+    }
+}
+
+impl<C: Context> Score<C> for Shared<dyn Score<C>> {
+    fn score(&self, ctx: &C) -> f32 {
+        self.0.score(ctx)
+    }
+}
+
+impl<C: Context> Operator<C> for Shared<dyn Operator<C>> {
+    fn on_start(&self, ctx: &mut C) {
+        self.0.on_start(ctx)
+    }
+
+    fn update(&self, ctx: &mut C) -> TaskStatus {
+        self.0.update(ctx)
+    }
+
+    fn on_stop(&self, ctx: &mut C, status: TaskStatus) {
+        self.0.on_stop(ctx, status)
+    }
+
+    fn on_interrupt(&self, ctx: &mut C) {
+        self.0.on_interrupt(ctx)
+    }
+}
+
+// Every context type gets its own behaviour asset type, so the uuid is the
+// context's own with some bits flipped.
+impl<C: Context + TypeUuid> TypeUuid for Behaviour<C> {
+    const TYPE_UUID: Uuid = flip_uuid(C::TYPE_UUID);
+}
+
+const fn flip_uuid(uuid: Uuid) -> Uuid {
+    const MASK: [u8; 16] = [
+        0x5b, 0x1e, 0x0c, 0x7a, 0x93, 0x2d, 0x41, 0xf8,
+        0xa6, 0x10, 0xe4, 0x3c, 0x88, 0x57, 0xd2, 0x6f,
+    ];
+    let bytes = uuid.as_bytes();
+    let mut flipped = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        flipped[i] = bytes[i] ^ MASK[i];
+        i += 1;
+    }
+    Uuid::from_bytes(flipped)
+}
+
+pub struct BehaviourLoader<C: Context> {
+    library: BehaviourLibrary<C>,
+    extensions: Vec<&'static str>,
+}
+
+impl<C: Context + TypeUuid> AssetLoader for BehaviourLoader<C> {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let def: BehaviourDef = ron::de::from_bytes(bytes)
+                .map_err(|e| BehaviourLoadError::Parse(e.to_string()))?;
+            let behaviour = self.library.build(&def)?;
+            load_context.set_default_asset(LoadedAsset::new(behaviour));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &self.extensions
+    }
+}
+
+/// Loads `Behaviour<C>` assets from RON files, and ticks agents with a
+/// `Handle<Behaviour<C>>` instead of a BehaviourName. Goes alongside HtnPlugin<C>, and
+/// has to be in the same stage as it. Editing a loaded file swaps the behaviour, and
+/// the planners using it interrupt what they're doing and start over.
+pub struct HtnAssetPlugin<C, S = CoreStage> {
+    extensions: Vec<&'static str>,
+    stage: S,
+    pd: PhantomData<C>,
+}
+
+impl<C: Context + Component + TypeUuid> Default for HtnAssetPlugin<C> {
+    fn default() -> Self {
+        HtnAssetPlugin::with_extensions(vec!["htn"])
+    }
+}
+
+impl<C: Context + Component + TypeUuid> HtnAssetPlugin<C> {
+    /// Each context type needs its own file extension if there's more than one
+    pub fn with_extensions(extensions: Vec<&'static str>) -> Self {
+        HtnAssetPlugin {
+            extensions,
+            stage: CoreStage::Update,
+            pd: PhantomData::default(),
+        }
+    }
+}
+
+impl<C, S> HtnAssetPlugin<C, S>
+where
+    C: Context + Component + TypeUuid,
+    S: StageLabel + Clone,
+{
+    /// Tick in the stage HtnPlugin::in_stage was given
+    pub fn in_stage<T: StageLabel + Clone>(self, stage: T) -> HtnAssetPlugin<C, T> {
+        HtnAssetPlugin {
+            extensions: self.extensions,
+            stage: stage,
+            pd: PhantomData::default(),
+        }
+    }
+}
+
+impl<C, S> Plugin for HtnAssetPlugin<C, S>
+where
+    C: Context + Component + TypeUuid,
+    S: StageLabel + Clone,
+{
+    fn build(&self, app: &mut App) {
+        let library = BehaviourLibrary::<C>::default();
+        app
+        .insert_resource(library.clone())
+        .add_asset::<Behaviour<C>>()
+        .add_asset_loader(BehaviourLoader {
+            library,
+            extensions: self.extensions.clone(),
+        })
+        .add_system_to_stage(self.stage.clone(), mark_asset_agents::<C>.exclusive_system().at_start())
+        .add_system_set_to_stage(
+            self.stage.clone(),
+            SystemSet::new()
+                .with_run_criteria(htn_enabled::<C>)
+                .with_system(tick_asset_planners::<C>.label(HtnSystem::Tick).after(HtnSystem::Clock)),
+        );
+    }
+}
+
+// exclusive like HtnPlugin's insert_planners, so new agents aren't ticked against the
+// registry before they're marked
+fn mark_asset_agents<C: Context + Component + TypeUuid>(world: &mut World) {
+    let new: Vec<Entity> = world
+        .query_filtered::<Entity, (With<Handle<Behaviour<C>>>, Without<FromBehaviourAsset>)>()
+        .iter(world)
+        .collect();
+    for entity in new {
+        world.entity_mut(entity).insert(FromBehaviourAsset);
+    }
+}
+
+fn tick_asset_planners<C: Context + Component + TypeUuid>(
+    behaviours: Res<Assets<Behaviour<C>>>,
+    clock: Res<HtnClock<C>>,
+    settings: Res<HtnSettings<C>>,
+    mut frame: Local<u64>,
+    mut q_agents: Query<(Entity, &mut C, &mut Planner<C>, &Handle<Behaviour<C>>), With<FromBehaviourAsset>>,
+) {
+    *frame += 1;
+    let mut waiting = vec![];
+    for (entity, mut ctx, mut planner, handle) in q_agents.iter_mut() {
+        // nothing to do until it's loaded
+        if let Some(behaviour) = behaviours.get(handle) {
+            if let Some((since, steps)) = prepare_within_budget(&mut planner, behaviour, &mut *ctx, clock.delta, *frame) {
+                waiting.push((since, entity, steps));
+            }
+        }
+    }
+    let budget = FrameBudget::new(&settings.budget, waiting);
+    q_agents.par_for_each_mut(settings.batch_size, |(entity, mut ctx, mut planner, handle)| {
+        if let Some(behaviour) = behaviours.get(handle) {
+            run_within_budget(&mut planner, behaviour, &mut *ctx, entity, &budget);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_asset::AssetPlugin;
+    use bevy_tasks::{IoTaskPool, TaskPool};
+
+    static HUNGRY: Key<bool> = Key::new("hungry");
+    static INTERRUPTED: Key<bool> = Key::new("interrupted");
+
+    fn test_library() -> BehaviourLibrary<BeingContext> {
+        let library = BehaviourLibrary::default();
+        library.add_condition("hungry", |ctx: &BeingContext| ctx.get(&HUNGRY).is_some());
+        library.add_effect("fed", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.remove(&HUNGRY));
+        library.add_operator("eat", |ctx: &mut BeingContext| TaskStatus::Success);
+        library.add_operator("wander", |ctx: &mut BeingContext| TaskStatus::Continue);
+        library
+    }
+
+    #[test]
+    fn builds_behaviour_from_ron() {
+        let behaviour = test_library().build_from_str(r#"
+            (
+                name: "Student",
+                root: Selector(name: "BeStudent", tasks: [
+                    Primitive(name: "Eat", conditions: ["hungry"], depends_on: ["hungry"], effects: ["fed"], operator: "eat"),
+                    Sequence(name: "Idle", tasks: [
+                        Primitive(name: "Wander", operator: "wander"),
+                        Pause,
+                    ]),
+                ]),
+            )
+        "#).unwrap();
+        assert_eq!(behaviour.name, "Student");
+        assert_eq!(behaviour.to_tree(), "BeStudent\n  Eat\n  Idle\n    Wander\n    Pause\n");
+
+        let mut ctx = BeingContext::default();
+        ctx.set(&HUNGRY, Variant::Bool(true));
+        let mut p = Planner::default();
+        p.tick(&behaviour, &mut ctx, 0.0);
+        assert!(ctx.get(&HUNGRY).is_none());
+    }
+
+    #[test]
+    fn unknown_names_are_reported() {
+        let error = test_library().build_from_str(r#"
+            (name: "Student", root: Sequence(name: "root", tasks: [
+                Primitive(name: "Sleep", operator: "sleep"),
+            ]))
+        "#).err().unwrap();
+        assert_eq!(error.to_string(), "no operator called 'sleep' in the library");
+
+        let error = test_library().build_from_str(r#"
+            (name: "Student", root: Sequence(name: "root", tasks: []))
+        "#).err().unwrap();
+        assert_eq!(error.to_string(), "compound task 'root' has no sub-tasks");
+    }
+
+    #[test]
+    fn reloading_interrupts_and_resets_planners() {
+        let mut app = App::new();
+        IoTaskPool::init(TaskPool::default);
+        app.add_plugin(AssetPlugin)
+            .add_plugin(HtnPlugin::<BeingContext>::default())
+            .add_plugin(HtnAssetPlugin::<BeingContext>::default());
+        let library = app.world.resource::<BehaviourLibrary<BeingContext>>().clone();
+        library.add_operator("work", FnOperator::new(|_: &mut BeingContext| TaskStatus::Continue)
+            .on_interrupt(|ctx: &mut BeingContext| ctx.set(&INTERRUPTED, Variant::Bool(true))));
+        library.add_operator("rest", |_: &mut BeingContext| TaskStatus::Continue);
+        let work = library.build_from_str(r#"
+            (name: "Worker", root: Sequence(name: "root", tasks: [Primitive(name: "Work", operator: "work")]))
+        "#).unwrap();
+        let handle = app.world.resource_mut::<Assets<Behaviour<BeingContext>>>().add(work);
+        let agent = app.world.spawn().insert(BeingContext::default()).insert(handle.clone()).id();
+
+        // planned and started the frame it's spawned
+        app.update();
+        assert_eq!(app.world.get::<Planner<BeingContext>>(agent).unwrap().current_task, Some(1));
+
+        let rest = library.build_from_str(r#"
+            (name: "Worker", root: Sequence(name: "root", tasks: [Primitive(name: "Rest", operator: "rest")]))
+        "#).unwrap();
+        let _ = app.world.resource_mut::<Assets<Behaviour<BeingContext>>>().set(&handle, rest);
+        app.update();
+
+        let ctx = app.world.get::<BeingContext>(agent).unwrap();
+        assert_eq!(ctx.get(&INTERRUPTED), Some(&Variant::Bool(true)));
+        let behaviours = app.world.resource::<Assets<Behaviour<BeingContext>>>();
+        let planner = app.world.get::<Planner<BeingContext>>(agent).unwrap();
+        assert_eq!(planner.current_task_name(behaviours.get(&handle).unwrap()), Some("Rest".to_owned()));
+    }
+}
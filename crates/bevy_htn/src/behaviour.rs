@@ -1,13 +1,30 @@
 use crate::prelude::*;
 use crate::task::*;
+use bevy_ecs::entity::Entity;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+static NEXT_BEHAVIOUR_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub const DEFAULT_MAX_DEPTH: usize = 32;
 
 pub struct Behaviour<C>
 where 
     C: Context
 {   
-    tasks: Vec<Task<C>>,
+    pub(crate) tasks: Vec<Task<C>>,
     pub name: String,
+    pub(crate) mode: DecompositionMode,
+    // how many references deep a decomposition can go
+    pub(crate) max_depth: usize,
+    // unique per behaviour built, so planners can tell when theirs has been swapped
+    // out from under them (e.g. an asset reload) and their task indices are stale
+    pub(crate) id: usize,
+    pub(crate) cache: Option<PlanCache>,
     pd: PhantomData<C>,
 }
 
@@ -17,10 +34,40 @@ impl<C: Context> Behaviour<C> {
         Behaviour::<C> {
             tasks: tasks,
             name: name.to_owned(),
+            mode: DecompositionMode::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            id: NEXT_BEHAVIOUR_ID.fetch_add(1, Ordering::Relaxed),
+            cache: None,
             pd: PhantomData::default(),
         }
     }
 
+    pub fn set_mode(&mut self, mode: DecompositionMode) {
+        self.mode = mode;
+    }
+
+    pub fn get_mode(&self) -> DecompositionMode {
+        self.mode
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn get_max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Starts an empty plan cache holding up to capacity decompositions, see
+    /// BehaviourBuilder::plan_cache
+    pub fn set_plan_cache(&mut self, capacity: usize) {
+        self.cache = Some(PlanCache::new(capacity));
+    }
+
+    pub fn plan_cache(&self) -> Option<&PlanCache> {
+        self.cache.as_ref()
+    }
+
     pub fn get_task(&self, index: usize) -> &Task<C> {
         self.tasks.get(index).expect("ERROR: wtf?? you tried to get a task from a behaviour at an index it doesn't have. This shouldn't happen!")
     }
@@ -29,36 +76,80 @@ impl<C: Context> Behaviour<C> {
         self.tasks.get_mut(index).expect("ERROR: wtf?? you tried to get a task from a behaviour at an index it doesn't have. This shouldn't happen!")
     }
 
+    pub fn task_name(&self, index: usize) -> Option<&str> {
+        self.tasks.get(index).map(|task| task.name.as_str())
+    }
+
+    /// The names from the root down to the task, e.g. "BeEnemy/MoveRandomly"
+    pub fn task_path(&self, index: usize) -> Option<String> {
+        self.tasks.get(index).map(|task| self.path_of(task.index))
+    }
+
+    pub(crate) fn path_of(&self, index: usize) -> String {
+        task_path(&self.tasks, index)
+    }
+
     pub fn find_plan(&self, ctx: &mut C) -> (Plan, DecompositionStatus) {
+        self.find_plan_with_slots(ctx, &Slots::default())
+    }
+
+    /// Like find_plan, with these behaviours in the slots
+    pub fn find_plan_with_slots(&self, ctx: &mut C, slots: &Slots<C>) -> (Plan, DecompositionStatus) {
+        self.find_plan_replacing(ctx, slots, &[])
+    }
+
+    // for a planner running these steps, which a plan from the same traversal replaces
+    // only if it planned them with different parameters
+    pub(crate) fn find_plan_replacing(&self, ctx: &mut C, slots: &Slots<C>, running: &[PlanStep]) -> (Plan, DecompositionStatus) {
+        let scope = Scope {
+            slots: slots,
+            slot: None,
+        };
         ctx.state_mut().exec_state = ExecutionState::Planning;
         // let mut plan_status: (Plan, DecompositionStatus);
         let mut plan = Plan::default();
         let mut status = DecompositionStatus::default();
 
+        ctx.state_mut().calls.clear();
+        ctx.state_mut().steps = 0;
+        ctx.state_mut().diverged = false;
+        ctx.state_mut().beaten = false;
+        ctx.state_mut().pending_cooldowns.clear();
+        ctx.state_mut().cooldown_wake = None;
+        ctx.state_mut().uncacheable = false;
+        // anything a rejected or failed decomposition did to the context gets undone
+        ctx.state_mut().begin_transaction();
         if ctx.state_mut().paused && ctx.state_mut().last_record.is_empty() {
             ctx.state_mut().paused = false;
-            status = self.resume_partial(ctx, &mut plan);
+            status = self.resume_partial(ctx, &mut plan, scope);
         } else {
-            status = self.full_replan(ctx, &mut plan);
+            status = self.full_replan(ctx, &mut plan, scope);
         }
 
-        // check if mtrs are same for optimisation
-        // plan_status.1 = self.check_mtrs(ctx);
-        // match status {
-        //     DecompositionStatus::Succeeded
-        //     | DecompositionStatus::Partial => {
-
-        //     },
-        //     _ => {
+        // the same traversal as the running plan gives the same plan, so replacing
+        // it would only throw away progress. Unless a binding read a key that's moved on.
+        use DecompositionStatus::*;
+        if (status == Succeeded || status == Partial)
+            && !ctx.state().last_record.is_empty()
+            && ctx.state().record == ctx.state().last_record
+            && ends_with(&plan, running)
+        {
+            status = Rejected;
+        }
 
-        //     }
-        // }
+        // only permanent effects outlive planning, the rest are reapplied as tasks succeed
+        match status {
+            Succeeded | Partial => ctx.state_mut().commit_permanent_only(),
+            _ => ctx.state_mut().rollback_transaction(),
+        }
+        ctx.state_mut().trace(TraceEvent::Finished(status));
 
+        ctx.state_mut().params.clear();
         ctx.state_mut().exec_state = ExecutionState::Executing;
         (plan, status)
     }
 
-    fn resume_partial(&self, ctx: &mut C, plan: &mut Plan) -> DecompositionStatus
+    fn resume_partial(&self, ctx: &mut C, plan: &mut Plan, scope: Scope<C>) -> DecompositionStatus
         where C: Context
     {
         use DecompositionStatus::*;
@@ -68,13 +159,13 @@ impl<C: Context> Behaviour<C> {
         while ctx.state().partial_queue.len() > 0 && !ctx.state().paused {
             let partial_task = &self.tasks[ctx.state_mut().partial_queue.pop_front().unwrap()];
             if !decomposed {
-                status = partial_task.decompose(ctx, &self, plan);
+                status = partial_task.decompose(ctx, &self, plan, scope);
                 decomposed = true;
             } else {
                 let mut new_plan = Plan::new();
-                status = partial_task.decompose(ctx, &self, &mut new_plan);
+                status = partial_task.decompose(ctx, &self, &mut new_plan, scope);
                 if status == Succeeded || status == Partial {
-                        plan.extend(new_plan.iter());
+                        plan.extend(new_plan.drain(..));
                 }
             }
         }
@@ -82,14 +173,14 @@ impl<C: Context> Behaviour<C> {
         // we failed to continue the paused partial plan, so we replan from root.
         if status == Rejected || status == Partial {
             ctx.state_mut().record.clear();
-            status = self.get_task(0).decompose(ctx, &self, plan);
+            status = self.get_task(0).decompose(ctx, &self, plan, scope);
         }
 
         status
     }
 
-    fn full_replan(&self, ctx: &mut C, plan: &mut Plan) -> DecompositionStatus {
-        let mut last_partial_plan = Plan::default();
+    fn full_replan(&self, ctx: &mut C, plan: &mut Plan, scope: Scope<C>) -> DecompositionStatus {
+        let mut last_partial_plan: VecDeque<usize> = VecDeque::new();
         let mut was_paused = false;
         if ctx.state().paused {
             ctx.state_mut().paused = false;
@@ -98,7 +189,7 @@ impl<C: Context> Behaviour<C> {
         }
 
         ctx.state_mut().record.clear();
-        let mut status = self.tasks[0].decompose(ctx, &self, plan);
+        let mut status = self.tasks[0].decompose(ctx, &self, plan, scope);
 
         use DecompositionStatus::*;
         if was_paused && (status == Rejected || status == Failed) {
@@ -110,42 +201,55 @@ impl<C: Context> Behaviour<C> {
         status
     }
 
-    // fn check_mtrs(&self, ctx: &mut BeingContext) -> DecompositionStatus {
-
-    // }
-
     pub fn print(&self) {
-        let mut stack: Vec<&Task<C>> = vec![];
-        for task in self.tasks.iter().rev() {
-            if task.parent.is_none() {
-                stack.push(&task);
-            }
-        }
-        let mut num_tabs = 0;
-        while stack.len() > 0 {
-            let current = stack.pop().unwrap();
-            println!("{:indent$}{name}", "", indent=num_tabs, name=current.name);
+        print!("{}", self.to_tree());
+    }
+
+    /// The task names as an indented tree, one task per line
+    pub fn to_tree(&self) -> String {
+        let mut tree = String::new();
+        // (task, depth) - children go on in reverse so they come off in order
+        let mut stack: Vec<(usize, usize)> = self.tasks.iter().rev()
+            .filter(|task| task.parent.is_none())
+            .map(|task| (task.index, 0))
+            .collect();
+        while let Some((index, depth)) = stack.pop() {
+            let current = &self.tasks[index];
+            // references aren't followed, so recursive behaviours still print
+            let repeat = format!(" x{}", current.repeat);
+            let (before, after) = match current.task_type {
+                TaskType::Reference => ("-> ", ""),
+                TaskType::Slot => ("[", "]"),
+                TaskType::Repeat => ("", repeat.as_str()),
+                _ => ("", ""),
+            };
+            tree.push_str(&format!("{:indent$}{}{}{}\n", "", before, current.name, after, indent=depth * 2));
             for child_index in current.sub_tasks.iter().rev() {
-                stack.push(&self.tasks[*child_index]);
-            }
-            if current.sub_tasks.len() == 0 {
-                if num_tabs >= 1 {
-                    num_tabs -= 1;
-                }
-            } else {
-                num_tabs += 1;
+                stack.push((*child_index, depth + 1));
             }
         }
+        tree
     }
 }
 
+// whether the steps still to run are what the end of the plan would have run
+fn ends_with(plan: &Plan, running: &[PlanStep]) -> bool {
+    plan.len() >= running.len() && plan.iter().skip(plan.len() - running.len()).eq(running.iter())
+}
+
 pub struct BehaviourBuilder<'s, C> 
     where C: Context
 {
     name: &'s str,
+    mode: DecompositionMode,
+    max_depth: usize,
+    plan_cache: Option<usize>,
     current_task: Option<usize>,
+    last_closed: Option<usize>,
     tasks: Vec<Task<C>>,
     task_stack: Vec<usize>,
+    // first mistake made while building, handed back from build()
+    error: Option<BehaviourError>,
     pd: PhantomData<C>,
 }
 
@@ -153,9 +257,14 @@ impl<'s, C: Context> BehaviourBuilder<'s, C> {
     pub fn new(name: &'s str) -> Self {
         BehaviourBuilder::<C> {
             name: name,
+            mode: DecompositionMode::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            plan_cache: None,
             current_task: None,
+            last_closed: None,
             tasks: vec![],
             task_stack: vec![],
+            error: None,
             pd: PhantomData::default(),
         }
     }
@@ -183,60 +292,494 @@ impl<'s, C: Context> BehaviourBuilder<'s, C> {
     }
 
     pub fn condition<K: Condition<C> + 'static>(&mut self, name: &str, condition: K) -> &mut Self {
-        self.tasks[self.current_task.unwrap()]
-            .conditions.push(Box::new(condition));
+        if let Some(index) = self.open_task("condition", name) {
+            self.tasks[index].conditions.push((name.to_owned(), Box::new(condition)));
+        }
+        self
+    }
+
+    /// A condition the open primitive has to keep meeting while it runs, checked before
+    /// every update. When it fails the task is interrupted and the planner replans.
+    pub fn exec_condition<K: Condition<C> + 'static>(&mut self, name: &str, condition: K) -> &mut Self {
+        if let Some(index) = self.open_task("exec_condition", name) {
+            if self.tasks[index].task_type != TaskType::Primitive {
+                let path = task_path(&self.tasks, index);
+                self.fail(BehaviourError::ExecConditionOnCompound(path));
+            } else {
+                self.tasks[index].exec_conditions.push((name.to_owned(), Box::new(condition)));
+            }
+        }
+        self
+    }
+
+    /// Declares a context key the open task's conditions read. When it changes, planners
+    /// whose plan (or anything that would beat it) depends on it replan. The
+    /// condition_compare family declare their own keys.
+    pub fn depends_on(&mut self, key: impl AsKey) -> &mut Self {
+        let key = key.key_id();
+        if let Some(index) = self.open_task("depends_on", &key.name()) {
+            if !self.tasks[index].depends_on(key) {
+                self.tasks[index].dependencies.push(key);
+            }
+        }
         self
     }
 
-    pub fn effect<E: Effect<C> + 'static>(&mut self, name: &str, effect: E) -> &mut Self {
-        self.tasks[self.current_task.unwrap()]
-            .effects.push(Box::new(effect));
+    /// Binds a parameter for the open task and everything under it, including whatever
+    /// references under it refer to. A sub-task binding the same name takes precedence.
+    /// Read with `ctx.param(name)` from the task's
+    /// conditions, effects and operator.
+    pub fn bind(&mut self, name: &str, binding: Binding) -> &mut Self {
+        if let Some(index) = self.open_task("bind", name) {
+            self.tasks[index].bindings.push((name.to_owned(), binding));
+        }
+        self
+    }
+
+    pub fn bind_value(&mut self, name: &str, value: Variant) -> &mut Self {
+        self.bind(name, Binding::Value(value))
+    }
+
+    /// Binds the parameter to whatever the context has at key when the task is planned
+    pub fn bind_key(&mut self, name: &str, key: impl AsKey) -> &mut Self {
+        let key = key.key_id();
+        self.bind(name, Binding::Key(key));
+        self.depends_on(key)
+    }
+
+    /// Checks a context state key against a value with the given comparison
+    pub fn condition_compare(&mut self, name: &str, key: impl AsKey, comparison: Comparison, value: Variant) -> &mut Self {
+        self.condition(name, CompareCondition {
+            key: key.key_id(),
+            comparison: comparison,
+            value: value,
+        });
+        self.depends_on(key)
+    }
+
+    pub fn condition_equal(&mut self, name: &str, key: impl AsKey, value: Variant) -> &mut Self {
+        self.condition_compare(name, key, Comparison::Equal, value)
+    }
+
+    pub fn condition_less(&mut self, name: &str, key: impl AsKey, value: Variant) -> &mut Self {
+        self.condition_compare(name, key, Comparison::Less, value)
+    }
+
+    pub fn condition_greater(&mut self, name: &str, key: impl AsKey, value: Variant) -> &mut Self {
+        self.condition_compare(name, key, Comparison::Greater, value)
+    }
+
+    /// Inclusive at both ends
+    pub fn condition_in_range(&mut self, name: &str, key: impl AsKey, min: Variant, max: Variant) -> &mut Self {
+        self.condition(name, InRangeCondition {
+            key: key.key_id(),
+            min: min,
+            max: max,
+        });
+        self.depends_on(key)
+    }
+
+    pub fn condition_contains_entity(&mut self, name: &str, key: impl AsKey, entity: Entity) -> &mut Self {
+        self.condition(name, ContainsEntityCondition {
+            key: key.key_id(),
+            entity: entity,
+        });
+        self.depends_on(key)
+    }
+
+    pub fn effect<E: Effect<C> + 'static>(&mut self, name: &str, effect_type: EffectType, effect: E) -> &mut Self {
+        if let Some(index) = self.open_task("effect", name) {
+            self.tasks[index].effects.push((name.to_owned(), effect_type, Box::new(effect)));
+        }
         self
     }
 
     pub fn do_action<O: Operator<C> + 'static>(&mut self, name: &str, operator: O) -> &mut Self {
-        self.tasks[self.current_task.unwrap()]
-            .add_operator(Box::new(operator));
+        if let Some(index) = self.open_task("do_action", name) {
+            if self.tasks[index].task_type != TaskType::Primitive {
+                let path = task_path(&self.tasks, index);
+                self.fail(BehaviourError::OperatorOnCompound(path));
+            } else {
+                self.tasks[index].add_operator(name, Arc::new(operator));
+            }
+        }
         self
     }
 
     pub fn end(&mut self) -> &mut Self {
+        if self.current_task.is_none() {
+            let path = self.last_closed.map_or("".to_owned(), |index| task_path(&self.tasks, index));
+            self.fail(BehaviourError::UnbalancedEnd(path));
+            return self;
+        }
         // pop task from stack
+        self.last_closed = self.current_task;
         self.current_task = self.task_stack.pop();
         self
     }
 
+    /// Like a selector, but tries the sub-tasks in order of their score() - highest
+    /// first, sub-tasks without one scoring 0
+    pub fn utility_selector(&mut self, name: &str) -> &mut Self {
+        self.create_task(name, TaskType::UtilitySelector);
+        self
+    }
+
+    /// How much the open task's utility selector wants it
+    pub fn score<S: Score<C> + 'static>(&mut self, name: &str, score: S) -> &mut Self {
+        if let Some(index) = self.open_task("score", name) {
+            self.tasks[index].score = Some((name.to_owned(), Box::new(score)));
+        }
+        self
+    }
+
+    /// Scores the open task by putting a context key through a curve
+    pub fn score_curve(&mut self, name: &str, key: impl AsKey, curve: Curve) -> &mut Self {
+        self.score(name, CurveScore {
+            key: key.key_id(),
+            curve: curve,
+        });
+        self.depends_on(key)
+    }
+
+    /// Makes the open utility selector break ties between equal scores randomly,
+    /// using the planner's random number generator. Does nothing on other tasks.
+    pub fn random_ties(&mut self) -> &mut Self {
+        if let Some(index) = self.open_task("random_ties", "") {
+            self.tasks[index].random_ties = true;
+        }
+        self
+    }
+
+    /// Like a selector, but tries the sub-tasks in a random order each time it's
+    /// planned, the ones with a bigger weight() more likely to come first. The order
+    /// comes from the planner's rng, see Planner::set_seed.
+    pub fn random_selector(&mut self, name: &str) -> &mut Self {
+        self.create_task(name, TaskType::RandomSelector);
+        self
+    }
+
+    /// How likely the open task's random selector is to try it first, relative to
+    /// the other sub-tasks. 1 if not set, 0 for only when everything else fails.
+    pub fn weight(&mut self, weight: f32) -> &mut Self {
+        if let Some(index) = self.open_task("weight", "") {
+            self.tasks[index].weight = weight;
+        }
+        self
+    }
+
+    /// Decomposes as the selector or sequence with this name, wherever it is in the
+    /// behaviour - including one this is inside of, for recursion. Like pause() it
+    /// has no body, so it doesn't need an end().
+    pub fn reference(&mut self, name: &str) -> &mut Self {
+        self.create_task(name, TaskType::Reference);
+        self.end()
+    }
+
+    /// A place for a selector or sequence decided per agent, by filling it with a
+    /// behaviour using Planner::fill_slot. Fails to decompose while it's empty. No
+    /// end() needed. The filling behaviour's own slots and pauses aren't supported.
+    pub fn slot(&mut self, name: &str) -> &mut Self {
+        self.create_task(name, TaskType::Slot);
+        self.end()
+    }
+
+    /// How many references deep a decomposition can go before giving up on the
+    /// branch, DEFAULT_MAX_DEPTH if not set
+    pub fn max_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// A sequence that does its sub-tasks over again, times times in all, at least once
+    pub fn repeat(&mut self, name: &str, times: usize) -> &mut Self {
+        self.create_task(name, TaskType::Repeat);
+        if let Some(index) = self.current_task {
+            self.tasks[index].repeat = times;
+        }
+        self
+    }
+
+    /// A primitive that succeeds once it's been running for this many seconds. No
+    /// end() needed.
+    pub fn wait(&mut self, name: &str, seconds: f32) -> &mut Self {
+        self.create_task(name, TaskType::Primitive);
+        self.do_action("wait", Wait { seconds: seconds });
+        self.end()
+    }
+
+    /// Fails the open primitive if it's still running after this many seconds
+    pub fn timeout(&mut self, seconds: f32) -> &mut Self {
+        if let Some(index) = self.open_task("timeout", "") {
+            if self.tasks[index].task_type != TaskType::Primitive {
+                let path = task_path(&self.tasks, index);
+                self.fail(BehaviourError::TimeoutOnCompound(path));
+            } else {
+                self.tasks[index].timeout = Some(seconds);
+            }
+        }
+        self
+    }
+
+    /// What doing the open primitive costs, for a GoapPlanner to find the cheapest
+    /// plan. 1 by default, and never negative. HTN planning ignores it.
+    pub fn cost(&mut self, cost: f32) -> &mut Self {
+        if let Some(index) = self.open_task("cost", "") {
+            if self.tasks[index].task_type != TaskType::Primitive {
+                let path = task_path(&self.tasks, index);
+                self.fail(BehaviourError::CostOnCompound(path));
+            } else if !(cost >= 0.0) {
+                // NaN too, the search can't order plans by it
+                let path = task_path(&self.tasks, index);
+                self.fail(BehaviourError::NegativeCost(path));
+            } else {
+                self.tasks[index].cost = cost;
+            }
+        }
+        self
+    }
+
+    /// Once a plan with the open task in it is accepted, the task can't be chosen
+    /// again for this many seconds. Planners it was held back from replan once it's over.
+    pub fn cooldown(&mut self, seconds: f32) -> &mut Self {
+        if let Some(index) = self.open_task("cooldown", "") {
+            self.tasks[index].cooldown = Some(seconds);
+        }
+        self
+    }
+
+    /// Pauses have no body, so unlike the other tasks they don't need an end()
     pub fn pause(&mut self) -> &mut Self {
-        self.create_task("", TaskType::Pause);
+        self.create_task("Pause", TaskType::Pause);
+        self.end()
+    }
+
+    /// Shares decompositions between the planners running the behaviour. A planner
+    /// starting a fresh plan reuses one made for the same values of the keys it read,
+    /// rather than decomposing again. Only safe if the conditions, scores and bindings
+    /// read nothing but the context state. Decompositions that used the rng, a
+    /// cooldown, a permanent effect or a slot aren't cached. Once it holds capacity
+    /// decompositions, the one used longest ago makes room for the next.
+    pub fn plan_cache(&mut self, capacity: usize) -> &mut Self {
+        self.plan_cache = Some(capacity);
         self
     }
 
-    pub fn build(self) -> Behaviour<C> {
-        Behaviour::<C>::new(self.name, self.tasks)
+    /// Let selectors backtrack into their other sub-tasks when a later part of the
+    /// plan fails, visiting at most max_steps tasks per decomposition
+    pub fn backtracking(&mut self, max_steps: usize) -> &mut Self {
+        self.mode = DecompositionMode::Backtracking { max_steps: max_steps };
+        self
+    }
+
+    pub fn build(mut self) -> Result<Behaviour<C>, BehaviourError> {
+        self.validate()?;
+        for index in 0..self.tasks.len() {
+            if self.tasks[index].task_type == TaskType::Reference {
+                self.tasks[index].target = Some(self.reference_target(index)?);
+            }
+        }
+        let mut behaviour = Behaviour::<C>::new(self.name, self.tasks);
+        behaviour.set_mode(self.mode);
+        behaviour.set_max_depth(self.max_depth);
+        if let Some(capacity) = self.plan_cache {
+            behaviour.set_plan_cache(capacity);
+        }
+        Ok(behaviour)
+    }
+
+    // the one compound task with the reference's name
+    fn reference_target(&self, index: usize) -> Result<usize, BehaviourError> {
+        let name = &self.tasks[index].name;
+        let mut targets = self.tasks.iter().filter(|task| {
+            &task.name == name
+            && matches!(
+                task.task_type,
+                TaskType::Selector | TaskType::Sequence | TaskType::UtilitySelector | TaskType::Repeat | TaskType::RandomSelector
+            )
+        });
+        match (targets.next(), targets.next()) {
+            (Some(target), None) => Ok(target.index),
+            (None, _) => Err(BehaviourError::UnknownReference(task_path(&self.tasks, index))),
+            (Some(_), Some(_)) => Err(BehaviourError::AmbiguousReference(task_path(&self.tasks, index))),
+        }
+    }
+
+    fn validate(&self) -> Result<(), BehaviourError> {
+        use BehaviourError::*;
+
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        if self.tasks.len() == 0 {
+            return Err(Empty);
+        }
+        if let Some(index) = self.current_task {
+            return Err(UnclosedTask(task_path(&self.tasks, index)));
+        }
+        for task in self.tasks.iter() {
+            let path = || task_path(&self.tasks, task.index);
+            match task.task_type {
+                _ if task.parent.is_none() && task.index != 0 => return Err(MultipleRoots(path())),
+                TaskType::Primitive if task.operator.is_none() => return Err(NoOperator(path())),
+                TaskType::Sequence | TaskType::Selector | TaskType::UtilitySelector | TaskType::Repeat
+                | TaskType::RandomSelector if task.sub_tasks.len() == 0 =>
+                {
+                    return Err(EmptyCompound(path()))
+                },
+                TaskType::Repeat if task.repeat == 0 => return Err(NeverRepeated(path())),
+                TaskType::Pause => {
+                    let in_sequence = task.parent
+                        .map_or(false, |parent| self.tasks[parent].task_type == TaskType::Sequence);
+                    if !in_sequence {
+                        return Err(PauseOutsideSequence(path()));
+                    }
+                },
+                _ => {},
+            }
+            let in_utility_selector = task.parent
+                .map_or(false, |parent| self.tasks[parent].task_type == TaskType::UtilitySelector);
+            if task.score.is_some() && !in_utility_selector {
+                return Err(ScoreOutsideUtilitySelector(path()));
+            }
+            let in_random_selector = task.parent
+                .map_or(false, |parent| self.tasks[parent].task_type == TaskType::RandomSelector);
+            if task.weight != 1.0 && !in_random_selector {
+                return Err(WeightOutsideRandomSelector(path()));
+            }
+        }
+        Ok(())
     }
 
     fn create_task(&mut self, name: &str, task_type: TaskType) {
         let new_index: usize = self.tasks.len();
+        if let Some(index) = self.current_task {
+            if self.tasks[index].task_type == TaskType::Primitive {
+                let path = format!("{}/{}", task_path(&self.tasks, index), name);
+                self.fail(BehaviourError::ChildOfPrimitive(path));
+            }
+        }
         self.tasks.push(Task::<C>::new(name, new_index, self.current_task, task_type));
         if let Some(index) = self.current_task {
             self.task_stack.push(index);
-            self.tasks[index].add_child(new_index);
+            if self.tasks[index].task_type != TaskType::Primitive {
+                self.tasks[index].add_child(new_index);
+            }
         }
         self.current_task = Some(new_index);
     }
+
+    // the task that conditions, effects etc. get added to
+    fn open_task(&mut self, call: &str, name: &str) -> Option<usize> {
+        if self.current_task.is_none() {
+            self.fail(BehaviourError::NoOpenTask(format!("{}(\"{}\")", call, name)));
+        }
+        self.current_task
+    }
+
+    fn fail(&mut self, error: BehaviourError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+}
+
+/// Something wrong with the shape of a behaviour, found when building it. Each one
+/// carries the path of the offending task from the root, e.g. "BeEnemy/MoveRandomly".
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BehaviourError {
+    /// No tasks were added at all
+    Empty,
+    /// end() was called with no task open; holds the last task that was closed
+    UnbalancedEnd(String),
+    /// A task was never closed with end()
+    UnclosedTask(String),
+    /// condition, effect or do_action was called with no task open
+    NoOpenTask(String),
+    OperatorOnCompound(String),
+    ChildOfPrimitive(String),
+    NoOperator(String),
+    EmptyCompound(String),
+    /// A second task with no parent, after the first root was closed
+    MultipleRoots(String),
+    PauseOutsideSequence(String),
+    /// A reference to a name no selector or sequence has
+    UnknownReference(String),
+    /// A reference to a name more than one selector or sequence has
+    AmbiguousReference(String),
+    /// A task has a score, but no utility selector to use it
+    ScoreOutsideUtilitySelector(String),
+    /// A task has a weight, but no random selector to use it
+    WeightOutsideRandomSelector(String),
+    TimeoutOnCompound(String),
+    CostOnCompound(String),
+    /// A cost below 0 or NaN, which would keep a GoapPlanner from finding the cheapest plan
+    NegativeCost(String),
+    ExecConditionOnCompound(String),
+    /// A repeat done 0 times
+    NeverRepeated(String),
+}
+
+impl fmt::Display for BehaviourError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BehaviourError::*;
+        match self {
+            Empty => write!(f, "behaviour has no tasks"),
+            UnbalancedEnd(path) => write!(f, "end() called with no open task, after '{}'", path),
+            UnclosedTask(path) => write!(f, "task '{}' is missing an end()", path),
+            NoOpenTask(call) => write!(f, "{} called with no open task", call),
+            OperatorOnCompound(path) => write!(f, "compound task '{}' can't have an operator", path),
+            ChildOfPrimitive(path) => write!(f, "task '{}' is inside a primitive task", path),
+            NoOperator(path) => write!(f, "primitive task '{}' has no operator", path),
+            EmptyCompound(path) => write!(f, "compound task '{}' has no sub-tasks", path),
+            MultipleRoots(path) => write!(f, "task '{}' is a second root, behaviours can only have one", path),
+            PauseOutsideSequence(path) => write!(f, "pause '{}' has to be inside a sequence", path),
+            UnknownReference(path) => write!(f, "reference '{}' doesn't match any selector or sequence", path),
+            AmbiguousReference(path) => write!(f, "reference '{}' matches more than one selector or sequence", path),
+            ScoreOutsideUtilitySelector(path) => write!(f, "task '{}' has a score but isn't in a utility selector", path),
+            WeightOutsideRandomSelector(path) => write!(f, "task '{}' has a weight but isn't in a random selector", path),
+            TimeoutOnCompound(path) => write!(f, "compound task '{}' can't have a timeout", path),
+            CostOnCompound(path) => write!(f, "compound task '{}' can't have a cost", path),
+            NegativeCost(path) => write!(f, "task '{}' can't have a negative cost", path),
+            ExecConditionOnCompound(path) => write!(f, "compound task '{}' can't have an exec condition", path),
+            NeverRepeated(path) => write!(f, "repeat '{}' has to be done at least once", path),
+        }
+    }
+}
+
+impl Error for BehaviourError {}
+
+fn task_path<C: Context>(tasks: &Vec<Task<C>>, index: usize) -> String {
+    let mut names = vec![tasks[index].name.as_str()];
+    let mut parent = tasks[index].parent;
+    while let Some(parent_index) = parent {
+        names.push(tasks[parent_index].name.as_str());
+        parent = tasks[parent_index].parent;
+    }
+    names.reverse();
+    names.join("/")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn noop(ctx: &mut BeingContext) -> TaskStatus {
+        TaskStatus::Success
+    }
+
     #[test]
     fn behaviour_basic_init() {
         let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
-        builder.selector("test").end();
-        let behaviour = builder.build();
+        builder
+            .selector("test")
+                .primitive("leaf")
+                    .do_action("noop", noop)
+                .end()
+            .end();
+        let behaviour = builder.build().unwrap();
 
-        assert!(behaviour.tasks.len() == 1);
+        assert!(behaviour.tasks.len() == 2);
         assert_eq!(behaviour.name, "test");
     }
 
@@ -246,26 +789,71 @@ mod tests {
         builder
             .sequence("test_parent")
                 .sequence("test_child")
+                    .primitive("leaf")
+                        .do_action("noop", noop)
+                    .end()
                 .end()
             .end();
-        let behav = builder.build();
-        assert_eq!(behav.tasks.len(), 2);
+        let behav = builder.build().unwrap();
+        assert_eq!(behav.tasks.len(), 3);
         assert_eq!(behav.tasks[0].sub_tasks.len(), 1);
     }
 
     #[test]
-    #[should_panic]
     fn adding_operator_to_compound_fails() {
         let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
         builder
             .selector("test_parent")
                 .do_action("durr", |ctx: &mut BeingContext| {TaskStatus::Success})
             .end();
-        let behav = builder.build();
+        let error = builder.build().err();
+        assert_eq!(error, Some(BehaviourError::OperatorOnCompound("test_parent".to_owned())));
+    }
+
+    #[test]
+    fn repeating_zero_times_fails() {
+        let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
+        builder
+            .repeat("test_parent", 0)
+                .primitive("child")
+                    .do_action("durr", |ctx: &mut BeingContext| {TaskStatus::Success})
+                .end()
+            .end();
+        let error = builder.build().err();
+        assert_eq!(error, Some(BehaviourError::NeverRepeated("test_parent".to_owned())));
+    }
+
+    #[test]
+    fn negative_or_nan_costs_fail() {
+        for cost in [-1.0, f32::NAN] {
+            let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
+            builder
+                .sequence("test_parent")
+                    .primitive("child")
+                        .cost(cost)
+                        .do_action("durr", |ctx: &mut BeingContext| {TaskStatus::Success})
+                    .end()
+                .end();
+            let error = builder.build().err();
+            assert_eq!(error, Some(BehaviourError::NegativeCost("test_parent/child".to_owned())));
+        }
+    }
+
+    #[test]
+    fn adding_exec_condition_to_compound_fails() {
+        let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
+        builder
+            .sequence("test_parent")
+                .exec_condition("durr", |ctx: &BeingContext| true)
+                .primitive("child")
+                    .do_action("durr", |ctx: &mut BeingContext| {TaskStatus::Success})
+                .end()
+            .end();
+        let error = builder.build().err();
+        assert_eq!(error, Some(BehaviourError::ExecConditionOnCompound("test_parent".to_owned())));
     }
 
     #[test]
-    #[should_panic]
     fn adding_task_to_primitive_fails() {
         let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("test");
         builder
@@ -273,6 +861,90 @@ mod tests {
                 .selector("durr")
                 .end()
             .end();
-        let behav = builder.build();
+        let error = builder.build().err();
+        assert_eq!(error, Some(BehaviourError::ChildOfPrimitive("test_parent/durr".to_owned())));
+    }
+
+    #[test]
+    fn malformed_behaviours_name_the_offending_task() {
+        use BehaviourError::*;
+
+        let build = |f: &dyn Fn(&mut BehaviourBuilder<BeingContext>)| {
+            let mut builder = BehaviourBuilder::new("test");
+            f(&mut builder);
+            builder.build().err()
+        };
+
+        assert_eq!(build(&|_| {}), Some(Empty));
+        assert_eq!(
+            build(&|b| { b.condition("too early", |ctx: &BeingContext| true); }),
+            Some(NoOpenTask("condition(\"too early\")".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.sequence("root").primitive("a").do_action("noop", noop).end().end().end(); }),
+            Some(UnbalancedEnd("root".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.sequence("root").primitive("a").do_action("noop", noop).end(); }),
+            Some(UnclosedTask("root".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.sequence("root").selector("empty").end().end(); }),
+            Some(EmptyCompound("root/empty".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.selector("root").primitive("lazy").end().end(); }),
+            Some(NoOperator("root/lazy".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| {
+                b.sequence("root").primitive("a").do_action("noop", noop).end().end()
+                    .sequence("root2").primitive("b").do_action("noop", noop).end().end();
+            }),
+            Some(MultipleRoots("root2".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.selector("root").primitive("a").do_action("noop", noop).end().pause().end(); }),
+            Some(PauseOutsideSequence("root/Pause".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.selector("root").reference("Patrol").end(); }),
+            Some(UnknownReference("root/Patrol".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| {
+                b.selector("root")
+                    .sequence("Idle").primitive("a").do_action("noop", noop).end().end()
+                    .selector("Idle").primitive("b").do_action("noop", noop).end().end()
+                    .reference("Idle")
+                .end();
+            }),
+            Some(AmbiguousReference("root/Idle".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| {
+                b.selector("root").primitive("a").score("never used", |ctx: &BeingContext| 1.0).do_action("noop", noop).end().end();
+            }),
+            Some(ScoreOutsideUtilitySelector("root/a".to_owned()))
+        );
+        assert_eq!(
+            build(&|b| { b.selector("root").primitive("a").weight(2.0).do_action("noop", noop).end().end(); }),
+            Some(WeightOutsideRandomSelector("root/a".to_owned()))
+        );
+    }
+
+    #[test]
+    fn recursive_behaviours_print() {
+        let mut builder = BehaviourBuilder::<BeingContext>::new("test");
+        builder
+            .selector("Patrol")
+                .sequence("Step")
+                    .primitive("Walk").do_action("noop", noop).end()
+                    .reference("Patrol")
+                .end()
+            .end();
+        let b = builder.build().unwrap();
+        assert_eq!(b.get_task(3).target, Some(0));
+        assert_eq!(b.to_tree(), "Patrol\n  Step\n    Walk\n    -> Patrol\n");
     }
-}
\ No newline at end of file
+}
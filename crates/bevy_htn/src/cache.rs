@@ -0,0 +1,254 @@
+use crate::context::Record;
+use crate::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Agents sharing a behaviour are often in the same situation as far as its conditions
+// can tell, and would decompose it to the same plan. A behaviour's cache remembers each
+// decomposition by the values of the keys it read, so the next agent that has the same
+// values gets the plan without decomposing. A value changing means a different
+// fingerprint, so nothing stale is ever reused.
+//
+// Only what's read through the context state is seen. Conditions that look anywhere
+// else (fields of the context, planner time) make a behaviour unsafe to cache.
+
+/// How useful a plan cache has been
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    /// Decompositions remembered right now
+    pub entries: usize,
+}
+
+impl CacheStats {
+    /// Hits out of all lookups, 0 before there's been any
+    pub fn hit_rate(&self) -> f32 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f32 / lookups as f32,
+        }
+    }
+}
+
+/// Shared by every planner running a behaviour, see BehaviourBuilder::plan_cache
+pub struct PlanCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    // the keys remembered decompositions read, in id order, and how many of them read each
+    read_sets: Vec<(Vec<KeyId>, usize)>,
+    // by the hash of the read keys' values
+    entries: HashMap<u64, Vec<CachedPlan>>,
+    len: usize,
+    // counts lookups and inserts, for which entry was used longest ago
+    clock: u64,
+}
+
+// a decomposition, and all it leaves behind that the planner needs
+#[derive(Clone)]
+pub(crate) struct CachedPlan {
+    values: Vec<(KeyId, Option<Variant>)>,
+    used: u64,
+    pub(crate) plan: Plan,
+    pub(crate) status: DecompositionStatus,
+    pub(crate) record: Record,
+    pub(crate) visited: Vec<(Option<usize>, usize)>,
+}
+
+impl PlanCache {
+    pub fn new(capacity: usize) -> Self {
+        PlanCache {
+            capacity: capacity,
+            inner: Mutex::default(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.inner.lock().unwrap().len,
+        }
+    }
+
+    /// Forgets every plan. The stats are kept.
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = CacheInner::default();
+    }
+
+    pub(crate) fn get(&self, state: &ContextState) -> Option<CachedPlan> {
+        let mut inner = self.inner.lock().unwrap();
+        let found = inner.read_sets.iter().find_map(|(keys, _)| {
+            let values = values_of(state, keys);
+            let fingerprint = fingerprint(&values);
+            let index = inner.entries.get(&fingerprint)?
+                .iter()
+                .position(|cached| cached.values == values)?;
+            Some((fingerprint, index))
+        });
+        let counter = if found.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let (fingerprint, index) = found?;
+        inner.clock += 1;
+        let clock = inner.clock;
+        let cached = &mut inner.entries.get_mut(&fingerprint)?[index];
+        cached.used = clock;
+        Some(cached.clone())
+    }
+
+    /// Remembers a decomposition that read these keys, unless it's one that can't be
+    /// replayed from the values alone
+    pub(crate) fn insert(&self, state: &ContextState, reads: Vec<KeyId>, plan: &(Plan, DecompositionStatus)) {
+        let replayable = matches!(plan.1, DecompositionStatus::Succeeded | DecompositionStatus::Failed);
+        if !replayable || state.uncacheable {
+            return;
+        }
+        let values = values_of(state, &reads);
+
+        let mut inner = self.inner.lock().unwrap();
+        let fingerprint = fingerprint(&values);
+        // planners that missed on the same values at once all decompose
+        let known = inner.entries.get(&fingerprint)
+            .map_or(false, |entries| entries.iter().any(|entry| entry.values == values));
+        if known || self.capacity == 0 {
+            return;
+        }
+        if inner.len >= self.capacity {
+            inner.evict_least_recently_used();
+        }
+        inner.clock += 1;
+        match inner.read_sets.iter_mut().find(|(keys, _)| *keys == reads) {
+            Some((_, count)) => *count += 1,
+            None => inner.read_sets.push((reads, 1)),
+        }
+        let cached = CachedPlan {
+            plan: plan.0.clone(),
+            status: plan.1,
+            record: state.record.clone(),
+            visited: state.visited.clone(),
+            values: values,
+            used: inner.clock,
+        };
+        inner.entries.entry(fingerprint).or_default().push(cached);
+        inner.len += 1;
+    }
+}
+
+impl CacheInner {
+    // a scan of every entry, but only once the cache is full, and it's done under the
+    // lock a lookup would take anyway
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter()
+            .flat_map(|(fingerprint, entries)| entries.iter().enumerate().map(move |(i, entry)| (entry.used, *fingerprint, i)))
+            .min();
+        let (fingerprint, index) = match oldest {
+            Some((_, fingerprint, index)) => (fingerprint, index),
+            None => return,
+        };
+        let entries = self.entries.get_mut(&fingerprint).unwrap();
+        let evicted = entries.swap_remove(index);
+        if entries.is_empty() {
+            self.entries.remove(&fingerprint);
+        }
+        self.len -= 1;
+        // a read set nothing read any more only slows lookups down
+        let keys: Vec<KeyId> = evicted.values.iter().map(|(key, _)| *key).collect();
+        if let Some(position) = self.read_sets.iter().position(|(read, _)| *read == keys) {
+            self.read_sets[position].1 -= 1;
+            if self.read_sets[position].1 == 0 {
+                self.read_sets.remove(position);
+            }
+        }
+    }
+}
+
+/// The keys a decomposition reads, a bit for each one. Conditions only get the context
+/// by reference, so they're marked through atomics rather than a lock every read takes.
+pub(crate) struct ReadSet {
+    bits: Vec<AtomicU64>,
+    // read a key interned after the set was made, so it has no bit
+    missed: AtomicBool,
+}
+
+impl ReadSet {
+    // with a bit for every key interned so far
+    pub(crate) fn new() -> Self {
+        ReadSet {
+            bits: (0..(KeyId::count() + 63) / 64).map(|_| AtomicU64::new(0)).collect(),
+            missed: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn mark(&self, key: KeyId) {
+        match self.bits.get(key.index() / 64) {
+            Some(bits) => { bits.fetch_or(1 << (key.index() % 64), Ordering::Relaxed); },
+            None => self.missed.store(true, Ordering::Relaxed),
+        }
+    }
+
+    /// The keys read, in id order. None if one of them couldn't be marked.
+    pub(crate) fn into_keys(self) -> Option<Vec<KeyId>> {
+        if self.missed.into_inner() {
+            return None;
+        }
+        let mut keys = vec![];
+        for (word, bits) in self.bits.into_iter().enumerate() {
+            let mut bits = bits.into_inner();
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                keys.push(KeyId::from_index(word * 64 + bit));
+                bits &= bits - 1;
+            }
+        }
+        Some(keys)
+    }
+}
+
+fn values_of(state: &ContextState, keys: &[KeyId]) -> Vec<(KeyId, Option<Variant>)> {
+    keys.iter().map(|key| (*key, state.get(key).cloned())).collect()
+}
+
+pub(crate) fn fingerprint(values: &[(KeyId, Option<Variant>)]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (key, value) in values.iter() {
+        key.hash(&mut hasher);
+        match value {
+            Some(value) => hash_variant(value, &mut hasher),
+            None => 0u8.hash(&mut hasher),
+        }
+    }
+    hasher.finish()
+}
+
+// Variants hold floats, so they can't just derive Hash. An Int32 and a Float that are
+// equal hash differently, which only costs a miss as entries are compared in full.
+fn hash_variant(variant: &Variant, hasher: &mut DefaultHasher) {
+    use Variant::*;
+    match variant {
+        Entity(entity) => (1u8, entity).hash(hasher),
+        Entities(entities) => (2u8, entities).hash(hasher),
+        Location(location) => (3u8, location.x.to_bits(), location.y.to_bits()).hash(hasher),
+        Bool(value) => (4u8, value).hash(hasher),
+        Int32(value) => (5u8, value).hash(hasher),
+        Float(value) => (6u8, value.to_bits()).hash(hasher),
+        Str(value) => (7u8, value).hash(hasher),
+        Map(map) => {
+            8u8.hash(hasher);
+            for (name, value) in map.iter() {
+                name.hash(hasher);
+                hash_variant(value, hasher);
+            }
+        }
+    }
+}
@@ -1,5 +1,15 @@
-use std::collections::{VecDeque, HashMap,};
-use bevy_ecs::entity::Entity;
+use std::cmp::Ordering;
+use std::collections::{VecDeque, HashMap, HashSet, BTreeMap,};
+use bevy_ecs::{component::Component, entity::Entity};
+use bevy_reflect::TypeUuid;
+use glam::Vec2;
+use rand::rngs::StdRng;
+use crate::cache::ReadSet;
+use crate::htn::Params;
+use crate::key::{AsKey, Key, KeyId, KeyType};
+use crate::trace::{DecompositionTrace, TraceEvent};
+
+pub use bevy_htn_derive::Context;
 
 pub trait Context: Send + Sync + 'static {
     fn state(&self) -> &ContextState;
@@ -9,12 +19,31 @@ pub trait Context: Send + Sync + 'static {
     // lets you add vars and such without haivng to say state-mut everywhere
 
     // cares if the key exists
-    fn add(&mut self, key: &str, variant: Variant);
+    fn add(&mut self, key: impl AsKey, variant: Variant);
     // doesn't care if the key exists
-    fn set(&mut self, key: &str, variant: Variant);
-    fn get(&self, key: &str) -> Option<&Variant>;
-    fn remove(&mut self, key: &str);
-    fn test_value(&self, key: &str, value: &Variant) -> Option<bool>;
+    fn set(&mut self, key: impl AsKey, variant: Variant);
+    fn get(&self, key: impl AsKey) -> Option<&Variant>;
+    fn remove(&mut self, key: impl AsKey);
+    fn test_value(&self, key: impl AsKey, value: &Variant) -> Option<bool>;
+
+    /// The value at a typed key, see ContextState::value
+    fn value<T: KeyType>(&self, key: &Key<T>) -> Option<&T> where Self: Sized {
+        self.state().value(key)
+    }
+
+    fn set_value<T: KeyType>(&mut self, key: &Key<T>, value: T) where Self: Sized {
+        self.state_mut().set_value(key, value)
+    }
+
+    /// Copies fields kept outside the state into it, so conditions can read them.
+    /// Planners call it at the start of every tick. derive(Context) implements it
+    /// for fields marked #[htn(key = "...")].
+    fn sync_keys(&mut self) {}
+
+    /// The current task's parameter, see ContextState::param
+    fn param(&self, name: &str) -> Option<&Variant> {
+        self.state().param(name)
+    }
 }
 
 pub struct ContextState {
@@ -23,9 +52,61 @@ pub struct ContextState {
     pub(crate) last_record: Record,
     pub(crate) partial_queue: VecDeque<usize>,
     pub(crate) paused: bool,
+    /// Forces a replan on the next tick, whatever has or hasn't changed
     pub dirty: bool,
-    vars: HashMap<String, Variant>,
-    transactions: Vec<Vec<String>>,
+    pub(crate) permanent_writes: bool,
+    // only Some while a planner with tracing on is decomposing
+    pub(crate) trace: Option<DecompositionTrace>,
+    // every task whose conditions were checked during the last decomposition, and
+    // the slot it was in
+    pub(crate) visited: Vec<(Option<usize>, usize)>,
+    // parameters of the task being planned or run
+    pub(crate) params: Params,
+    // the references the decomposition is inside, and the slot each is in, innermost
+    // last. Their call sites bind the parameters of what they refer to.
+    pub(crate) calls: Vec<(Option<usize>, usize)>,
+    // tasks visited by the decomposition, for the planning budget
+    pub(crate) steps: usize,
+    // set once a utility selector picks differently than the running plan did, after
+    // which the rest of the records can't be compared
+    pub(crate) diverged: bool,
+    // set once a selector picks an earlier sub-task than the running plan did, after
+    // which the new plan is higher priority whatever comes next
+    pub(crate) beaten: bool,
+    // only Some while a planner is decomposing, for utility selector tie breaks
+    pub(crate) rng: Option<StdRng>,
+    // seconds the planner has been ticked for, and by how much last tick
+    pub(crate) now: f64,
+    pub(crate) delta: f32,
+    // when the current task started
+    pub(crate) task_started: f64,
+    // when each task with a cooldown (and the slot it was in) can be chosen again
+    pub(crate) cooldowns: HashMap<(Option<usize>, usize), f64>,
+    // cooldowns of the tasks in the plan being found, started if it's accepted
+    pub(crate) pending_cooldowns: Vec<((Option<usize>, usize), f32)>,
+    // the soonest a cooldown that turned a task down while planning runs out
+    pub(crate) cooldown_wake: Option<f64>,
+    // keys read while a decomposition is being remembered by a plan cache
+    pub(crate) reads: Option<ReadSet>,
+    // set once a decomposition does something a plan cache couldn't replay - using
+    // the rng, a cooldown or a permanent effect
+    pub(crate) uncacheable: bool,
+    // indexed by KeyId, so reads and writes never hash
+    vars: Vec<Option<Variant>>,
+    transactions: Vec<Journal>,
+    // keys changed outside of planning since the planner last looked
+    changed: HashSet<KeyId>,
+}
+
+// undo journal for a single transaction - every change to a key records what
+// was there before (None if the key didn't exist) so rollback can put it back
+type Journal = Vec<JournalEntry>;
+
+struct JournalEntry {
+    key: KeyId,
+    last_value: Option<Variant>,
+    // made by a permanent effect, so it survives commit_permanent_only
+    permanent: bool,
 }
 
 impl Default for ContextState {
@@ -37,8 +118,26 @@ impl Default for ContextState {
             partial_queue: VecDeque::default(),
             paused: bool::default(),
             dirty: true,
-            vars: HashMap::default(),
+            permanent_writes: false,
+            trace: None,
+            visited: vec![],
+            params: Params::default(),
+            calls: vec![],
+            steps: 0,
+            diverged: false,
+            beaten: false,
+            rng: None,
+            now: 0.0,
+            delta: 0.0,
+            task_started: 0.0,
+            cooldowns: HashMap::default(),
+            pending_cooldowns: vec![],
+            cooldown_wake: None,
+            reads: None,
+            uncacheable: false,
+            vars: vec![],
             transactions: vec![],
+            changed: HashSet::default(),
         }
     }
 }
@@ -55,73 +154,226 @@ impl ContextState {
     }
 
     // this key must NOT exist before you add it
-    pub fn add(&mut self, key: &str, variant: Variant) {
-        let last_value = self.vars.insert(key.to_string(), variant);
+    pub fn add(&mut self, key: impl AsKey, variant: Variant) {
+        let key = key.key_id();
+        let last_value = self.slot(key).replace(variant);
         assert!(last_value.is_none());
-        self.add_trans_key_if_needed(key);
+        self.mark_changed(key);
+        self.journal(key, last_value);
     }
 
     // doesn't care if the key exists
-    pub fn set(&mut self, key: &str, variant: Variant) {
-        let last_value = self.vars.insert(key.to_string(), variant);
-        if last_value.is_none() {
-            self.add_trans_key_if_needed(key);
+    pub fn set(&mut self, key: impl AsKey, variant: Variant) {
+        let key = key.key_id();
+        let slot = self.slot(key);
+        // setting the same value again every frame shouldn't look like a change
+        let changed = slot.as_ref() != Some(&variant);
+        let last_value = slot.replace(variant);
+        if changed {
+            self.mark_changed(key);
         }
+        self.journal(key, last_value);
     }
 
-    pub fn get(&self, key: &str) -> Option<&Variant> {
-        self.vars.get(key)
+    pub fn get(&self, key: impl AsKey) -> Option<&Variant> {
+        let key = key.key_id();
+        // even if it's missing, as it being set later changes the plan too
+        if let Some(reads) = &self.reads {
+            reads.mark(key);
+        }
+        self.vars.get(key.index())?.as_ref()
     }
 
-    pub fn remove(&mut self, key: &str) {
-        let last_value = self.vars.remove(key);
+    pub fn remove(&mut self, key: impl AsKey) {
+        let key = key.key_id();
+        let last_value = self.vars.get_mut(key.index()).and_then(|slot| slot.take());
         if last_value.is_some() {
-            self.remove_trans_key_if_needed(key);
+            self.mark_changed(key);
+            self.journal(key, last_value);
         }
     }
 
-    pub fn test_value(&self, key: &str, value: &Variant) -> Option<bool> {
+    /// The value at a typed key. None if it isn't set, or holds some other type of
+    /// Variant because it was set untyped.
+    pub fn value<T: KeyType>(&self, key: &Key<T>) -> Option<&T> {
+        T::from_variant(self.get(key)?)
+    }
+
+    pub fn set_value<T: KeyType>(&mut self, key: &Key<T>, value: T) {
+        self.set(key, value.into_variant());
+    }
+
+    pub fn remove_value<T: KeyType>(&mut self, key: &Key<T>) {
+        self.remove(key);
+    }
+
+    /// A parameter bound for the task being planned, or the one currently running. Only
+    /// makes sense from inside that task's conditions, effects and operator.
+    pub fn param(&self, name: &str) -> Option<&Variant> {
+        self.params.get(name)
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Seconds of planner time, the sum of the deltas it's been ticked with
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// The delta the planner was last ticked with
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    /// How long the current task has been running
+    pub fn task_time(&self) -> f32 {
+        (self.now - self.task_started) as f32
+    }
+
+    /// Seconds until a task with a cooldown can be chosen again, 0 if it already can
+    pub(crate) fn cooldown_left(&self, slot: Option<usize>, task: usize) -> f64 {
+        self.cooldowns.get(&(slot, task)).map_or(0.0, |ready| (ready - self.now).max(0.0))
+    }
+
+    /// Keys changed since the planner last checked. Changes made while planning don't count.
+    pub fn changed(&self) -> impl Iterator<Item = KeyId> + '_ {
+        self.changed.iter().copied()
+    }
+
+    pub fn has_changed(&self, key: impl AsKey) -> bool {
+        self.changed.contains(&key.key_id())
+    }
+
+    pub fn clear_changes(&mut self) {
+        self.changed.clear();
+    }
+
+    pub fn test_value(&self, key: impl AsKey, value: &Variant) -> Option<bool> {
         if let Some(this_value) = self.get(key) {
             return Some(this_value == value)
         }   
         None
     }
+
+    /// Orders the value at key against the given one. None if the key doesn't exist
+    /// or the two variants can't be ordered (e.g. a Bool against an Int32).
+    pub fn compare(&self, key: impl AsKey, value: &Variant) -> Option<Ordering> {
+        self.get(key)?.partial_cmp(value)
+    }
+
+    pub fn contains_entity(&self, key: impl AsKey, entity: Entity) -> Option<bool> {
+        match self.get(key)? {
+            Variant::Entity(e) => Some(*e == entity),
+            Variant::Entities(entities) => Some(entities.contains(&entity)),
+            _ => None,
+        }
+    }
     
+    /// What the open transactions have changed, in key id order, None for a key they
+    /// removed. Keys changed and then put back don't count, so two transactions from
+    /// the same start that end up in the same state give the same changes.
+    pub(crate) fn transaction_changes(&self) -> Vec<(KeyId, Option<Variant>)> {
+        // the first entry for a key has what it was before any of them
+        let mut before: BTreeMap<KeyId, &Option<Variant>> = BTreeMap::new();
+        for entry in self.transactions.iter().flatten() {
+            before.entry(entry.key).or_insert(&entry.last_value);
+        }
+        before.into_iter()
+            .map(|(key, last_value)| (key, last_value, self.vars.get(key.index()).cloned().flatten()))
+            .filter(|(_, last_value, value)| *last_value != value)
+            .map(|(key, _, value)| (key, value))
+            .collect()
+    }
+
     pub fn begin_transaction(&mut self) {
         self.transactions.push(vec![]);
     }
 
+    /// Undoes every change made since the matching begin_transaction, newest first,
+    /// so each key ends up exactly as it was (including not existing at all).
     pub fn rollback_transaction(&mut self) {
-        assert!(self.transactions.len() > 0);
-        for key in self.transactions.last().unwrap() {
-            self.vars.remove(key).expect("Rolled back a key that didn't exist - what on earth?! That should never happen.");
+        let journal = self.transactions.pop().expect("Rolled back with no transaction open!");
+        for entry in journal.into_iter().rev() {
+            self.restore(entry);
         }
-        self.transactions.pop();
     }
 
+    /// Rolls back everything except the changes made by permanent effects, which are
+    /// committed. Used at the end of planning so plan-only effects don't leak out.
+    pub fn commit_permanent_only(&mut self) {
+        let journal = self.transactions.pop().expect("Committed with no transaction open!");
+        let mut kept = Journal::default();
+        // keys written permanently later on shouldn't be restored to older values
+        let mut permanent_keys = HashSet::new();
+        for entry in journal.into_iter().rev() {
+            if entry.permanent {
+                permanent_keys.insert(entry.key);
+                kept.push(entry);
+            } else if !permanent_keys.contains(&entry.key) {
+                self.restore(entry);
+            }
+        }
+        if let Some(parent) = self.transactions.last_mut() {
+            parent.extend(kept.into_iter().rev());
+        }
+    }
+
+    /// Keeps the changes. If this was a nested transaction then its journal is handed
+    /// to the parent, so rolling the parent back still undoes them.
     pub fn commit_transaction(&mut self) {
-        assert!(self.transactions.len() > 0);
-        self.transactions.pop();
+        let journal = self.transactions.pop().expect("Committed with no transaction open!");
+        if let Some(parent) = self.transactions.last_mut() {
+            parent.extend(journal);
+        }
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transactions.len() > 0
     }
 
-    fn add_trans_key_if_needed(&mut self, key: &str) {
+    // the value slot for a key, growing the table to fit it
+    fn slot(&mut self, key: KeyId) -> &mut Option<Variant> {
+        if self.vars.len() <= key.index() {
+            self.vars.resize(key.index() + 1, None);
+        }
+        &mut self.vars[key.index()]
+    }
+
+    fn mark_changed(&mut self, key: KeyId) {
+        if !self.in_transaction() {
+            self.changed.insert(key);
+        }
+    }
+
+    fn journal(&mut self, key: KeyId, last_value: Option<Variant>) {
+        let permanent = self.permanent_writes;
         if let Some(transaction) = self.transactions.last_mut() {
-            transaction.push(key.to_string());
+            transaction.push(JournalEntry {
+                key: key,
+                last_value: last_value,
+                permanent: permanent,
+            });
         }
     }
 
-    fn remove_trans_key_if_needed(&mut self, key: &str) {
-        if let Some(transaction) = self.transactions.last() {
-            if let Some(pos) = transaction.iter().position(|x| *x == key) {
-                self.transactions.swap_remove(pos);
-            }
+    pub(crate) fn trace(&mut self, event: TraceEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(event);
         }
     }
+
+    fn restore(&mut self, entry: JournalEntry) {
+        *self.slot(entry.key) = entry.last_value;
+    }
 }
 
 // context for creatures, humans, etc
-#[derive(Default,)]
+#[derive(Component, TypeUuid, Default, Context)]
+#[uuid = "9a3e6c2b-54f1-4c8e-b7d0-2f61e8a4c935"]
 pub struct BeingContext {
+    #[htn(state)]
     state: ContextState,
 }
 
@@ -138,36 +390,68 @@ impl BeingContext {
 
 }
 
-impl Context for BeingContext {
-
-    fn state(&self) -> &ContextState {
-        &self.state
-    }
-
-    fn state_mut(&mut self) -> &mut ContextState {
-        &mut self.state
-    }
-
-    fn add(&mut self, key: &str, variant: Variant) { self.state.add(key, variant) }
-    fn set(&mut self, key: &str, variant: Variant) { self.state.set(key, variant) }
-    fn get(&self, key: &str) -> Option<&Variant> { self.state.get(key) }
-    fn remove(&mut self, key: &str) { self.state.remove(key) }
-    fn test_value(&self, key: &str, value: &Variant) -> Option<bool> { self.state.test_value(key, value) }
-
-}
-
 // wrappings of various things that can exist in the game world
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Debug, Clone)]
 pub enum Variant {
     Entity(Entity),
     Entities(Vec<Entity>),
-    Location, // empty for now
+    Location(Vec2),
     Bool(bool),
     Int32(i32),
+    Float(f32),
+    Str(String),
+    Map(BTreeMap<String, Variant>),
 }
 
-// snapshots the current task planned by the behaviour
-#[derive(Default,)]
+impl Variant {
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Variant::Int32(i) => Some(*i as f32),
+            Variant::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+// numbers equal each other across Int32 and Float the same way they order, so Equal
+// conditions agree with the ordered ones. Everything else has to be the same variant.
+impl PartialEq for Variant {
+    fn eq(&self, other: &Self) -> bool {
+        use Variant::*;
+        match (self, other) {
+            (Entity(a), Entity(b)) => a == b,
+            (Entities(a), Entities(b)) => a == b,
+            (Location(a), Location(b)) => a == b,
+            (Bool(a), Bool(b)) => a == b,
+            (Int32(a), Int32(b)) => a == b,
+            (Str(a), Str(b)) => a == b,
+            (Map(a), Map(b)) => a == b,
+            (Int32(_), Float(_)) | (Float(_), Int32(_)) | (Float(_), Float(_)) => {
+                self.as_f32() == other.as_f32()
+            },
+            _ => false,
+        }
+    }
+}
+
+// numbers order against each other (ints get widened to floats if needed),
+// strings order lexically, and nothing else has an ordering
+impl PartialOrd for Variant {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        use Variant::*;
+        match (self, other) {
+            (Int32(a), Int32(b)) => a.partial_cmp(b),
+            (Str(a), Str(b)) => a.partial_cmp(b),
+            (Bool(a), Bool(b)) => a.partial_cmp(b),
+            _ => self.as_f32()?.partial_cmp(&other.as_f32()?),
+        }
+    }
+}
+
+// method traversal record - which sub-task each selector chose during decomposition,
+// in the order the selectors were visited. Comparing against the record of the running
+// plan tells us whether a new decomposition is actually higher priority.
+#[derive(Default, PartialEq, Eq, Clone, Debug)]
 pub struct Record {
     tasks: Vec<usize>,
 }
@@ -181,6 +465,18 @@ impl Record {
         self.tasks.push(task_index);
     }
 
+    pub fn get(&self, position: usize) -> Option<usize> {
+        self.tasks.get(position).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.tasks.truncate(len);
+    }
+
     pub fn is_empty(&self) -> bool {
         self.tasks.len() == 0
     }
@@ -205,36 +501,166 @@ impl Default for ExecutionState {
 mod tests {
     use super::*;
 
+    static TEST1: Key<i32> = Key::new("test1");
+    static TEST2: Key<bool> = Key::new("test2");
+    static TEST3: Key<i32> = Key::new("test3");
+    static HUNGER: Key<i32> = Key::new("hunger");
+    static TIRED: Key<bool> = Key::new("tired");
+    static IN_CLASS: Key<bool> = Key::new("in_class");
+    static FATIGUE: Key<f32> = Key::new("fatigue");
+    static NAME: Key<String> = Key::new("name");
+    static NEW_KEY: Key<i32> = Key::new("new_key");
+    static MISSING: Key<i32> = Key::new("missing");
+    static SEAT: Key<i32> = Key::new("seat");
+    static CLAIMED: Key<bool> = Key::new("claimed");
+    static PLAN_ONLY: Key<bool> = Key::new("plan_only");
+
     #[test]
     fn rollback_works() {
         let mut ctx = BeingContext::new();
         ctx.state_mut().begin_transaction();
-        ctx.set("test1", Variant::Int32(10));
-        ctx.set("test2", Variant::Bool(true));
+        ctx.set(&TEST1, Variant::Int32(10));
+        ctx.set(&TEST2, Variant::Bool(true));
         ctx.state_mut().rollback_transaction();
 
-        assert!(ctx.state.get("test1").is_none());
-        assert!(ctx.state.get("test2").is_none());
+        assert!(ctx.state.get(&TEST1).is_none());
+        assert!(ctx.state.get(&TEST2).is_none());
         assert!(ctx.state.transactions.len() == 0);
 
-        ctx.set("test3", Variant::Int32(20));
+        ctx.set(&TEST3, Variant::Int32(20));
         assert!(ctx.state.transactions.len() == 0);
     }
 
+    #[test]
+    fn only_real_changes_outside_planning_are_tracked() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&HUNGER, Variant::Int32(10));
+        ctx.set(&TIRED, Variant::Bool(false));
+        assert!(ctx.state.has_changed(&HUNGER));
+        ctx.state_mut().clear_changes();
+
+        ctx.set(&HUNGER, Variant::Int32(10));
+        ctx.state_mut().begin_transaction();
+        ctx.set(&TIRED, Variant::Bool(true));
+        ctx.state_mut().rollback_transaction();
+        assert_eq!(ctx.state.changed().count(), 0);
+
+        ctx.remove(&TIRED);
+        assert!(ctx.state.has_changed(&TIRED));
+        assert!(!ctx.state.has_changed(&HUNGER));
+    }
+
+    #[test]
+    fn typed_keys_read_back_their_own_type() {
+        let mut ctx = BeingContext::new();
+        ctx.set_value(&HUNGER, 10);
+        assert_eq!(ctx.value(&HUNGER), Some(&10));
+        assert_eq!(ctx.get(&HUNGER), Some(&Variant::Int32(10)));
+        assert!(ctx.state.has_changed(&HUNGER));
+        assert_eq!(ctx.value(&TIRED), None);
+
+        // set untyped as the wrong type, so there's no value of the key's type
+        ctx.set(&TIRED, Variant::Int32(1));
+        assert_eq!(ctx.value(&TIRED), None);
+
+        ctx.state_mut().begin_transaction();
+        ctx.set_value(&TIRED, true);
+        ctx.state_mut().rollback_transaction();
+        assert_eq!(ctx.get(&TIRED), Some(&Variant::Int32(1)));
+    }
+
     #[test]
     fn commit_works() {
         let mut ctx = BeingContext::new();
         ctx.state_mut().begin_transaction();
-        ctx.set("test1", Variant::Int32(10));
-        ctx.set("test2", Variant::Bool(true));
+        ctx.set(&TEST1, Variant::Int32(10));
+        ctx.set(&TEST2, Variant::Bool(true));
         ctx.state_mut().commit_transaction();
 
-        assert!(ctx.state.get("test1").is_some());
-        assert!(ctx.state.get("test2").is_some());
+        assert!(ctx.state.get(&TEST1).is_some());
+        assert!(ctx.state.get(&TEST2).is_some());
         assert!(ctx.state.transactions.len() == 0);
 
-        ctx.set("test3", Variant::Int32(20));
+        ctx.set(&TEST3, Variant::Int32(20));
         assert!(ctx.state.transactions.len() == 0);
     }
 
+    #[test]
+    fn rollback_restores_overwritten_and_removed() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&IN_CLASS, Variant::Bool(false));
+        ctx.set(&HUNGER, Variant::Int32(10));
+        ctx.state_mut().begin_transaction();
+        ctx.set(&IN_CLASS, Variant::Bool(true));
+        ctx.set(&IN_CLASS, Variant::Bool(false));
+        ctx.set(&IN_CLASS, Variant::Bool(true));
+        ctx.remove(&HUNGER);
+        ctx.set(&HUNGER, Variant::Int32(50));
+        ctx.state_mut().rollback_transaction();
+
+        assert_eq!(ctx.get(&IN_CLASS), Some(&Variant::Bool(false)));
+        assert_eq!(ctx.get(&HUNGER), Some(&Variant::Int32(10)));
+    }
+
+    #[test]
+    fn nested_commit_is_undone_by_parent_rollback() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&IN_CLASS, Variant::Bool(false));
+        ctx.state_mut().begin_transaction();
+        ctx.state_mut().begin_transaction();
+        ctx.set(&IN_CLASS, Variant::Bool(true));
+        ctx.set(&NEW_KEY, Variant::Int32(1));
+        ctx.state_mut().commit_transaction();
+        assert_eq!(ctx.get(&IN_CLASS), Some(&Variant::Bool(true)));
+        ctx.state_mut().rollback_transaction();
+
+        assert_eq!(ctx.get(&IN_CLASS), Some(&Variant::Bool(false)));
+        assert!(ctx.get(&NEW_KEY).is_none());
+        assert!(!ctx.state().in_transaction());
+    }
+
+    #[test]
+    fn compare_orders_numbers_and_rejects_mismatches() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&HUNGER, Variant::Int32(60));
+        ctx.set(&FATIGUE, Variant::Float(0.5));
+        ctx.set(&NAME, Variant::Str("Bob".to_string()));
+
+        assert_eq!(ctx.state().compare(&HUNGER, &Variant::Int32(50)), Some(Ordering::Greater));
+        assert_eq!(ctx.state().compare(&HUNGER, &Variant::Float(60.5)), Some(Ordering::Less));
+        assert_eq!(ctx.state().compare(&FATIGUE, &Variant::Float(0.5)), Some(Ordering::Equal));
+        assert_eq!(ctx.state().compare(&NAME, &Variant::Int32(1)), None);
+        assert_eq!(ctx.state().compare(&MISSING, &Variant::Int32(1)), None);
+    }
+
+    #[test]
+    fn equality_agrees_with_ordering_across_numbers() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&HUNGER, Variant::Int32(50));
+
+        assert_eq!(ctx.state().compare(&HUNGER, &Variant::Float(50.0)), Some(Ordering::Equal));
+        assert_eq!(ctx.state().test_value(&HUNGER, &Variant::Float(50.0)), Some(true));
+        assert_eq!(ctx.state().test_value(&HUNGER, &Variant::Float(50.5)), Some(false));
+        assert_ne!(Variant::Int32(1), Variant::Bool(true));
+        assert_ne!(Variant::Str("1".to_owned()), Variant::Int32(1));
+    }
+
+    #[test]
+    fn commit_permanent_only_keeps_permanent_writes() {
+        let mut ctx = BeingContext::new();
+        ctx.set(&SEAT, Variant::Int32(0));
+        ctx.state_mut().begin_transaction();
+        ctx.set(&PLAN_ONLY, Variant::Bool(true));
+        ctx.set(&SEAT, Variant::Int32(1));
+        ctx.state_mut().permanent_writes = true;
+        ctx.set(&SEAT, Variant::Int32(2));
+        ctx.set(&CLAIMED, Variant::Bool(true));
+        ctx.state_mut().permanent_writes = false;
+        ctx.state_mut().commit_permanent_only();
+
+        assert!(ctx.get(&PLAN_ONLY).is_none());
+        assert_eq!(ctx.get(&SEAT), Some(&Variant::Int32(2)));
+        assert_eq!(ctx.get(&CLAIMED), Some(&Variant::Bool(true)));
+    }
+
 }
\ No newline at end of file
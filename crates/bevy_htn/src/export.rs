@@ -0,0 +1,199 @@
+use crate::prelude::*;
+use crate::task::*;
+
+// Diagram exporters for behaviours, so a behaviour change can be reviewed as a
+// picture. Pass a planner to highlight what it's planned and what it's doing now.
+
+impl<C: Context> Behaviour<C> {
+    /// Graphviz DOT, e.g. `dot -Tsvg behaviour.dot -o behaviour.svg`
+    pub fn to_dot(&self, planner: Option<&Planner<C>>) -> String {
+        let mut dot = format!("digraph \"{}\" {{\n", escape_dot(&self.name));
+        dot.push_str("    node [fontname=\"Helvetica\", style=filled, fillcolor=white];\n");
+        for task in self.tasks.iter() {
+            let shape = match task.task_type {
+                TaskType::Sequence => "box",
+                TaskType::Selector => "diamond",
+                TaskType::UtilitySelector => "hexagon",
+                TaskType::Primitive => "ellipse",
+                TaskType::Pause => "octagon",
+                TaskType::Reference => "cds",
+                TaskType::Slot => "folder",
+                TaskType::Repeat => "box3d",
+                TaskType::RandomSelector => "Mdiamond",
+            };
+            let fill = match highlight(task.index, planner) {
+                Highlight::Current => "gold",
+                Highlight::Planned => "lightblue",
+                Highlight::None => "white",
+            };
+            dot.push_str(&format!(
+                "    t{} [label=\"{}\", shape={}, fillcolor={}];\n",
+                task.index,
+                escape_dot(&label(task, "\n")),
+                shape,
+                fill,
+            ));
+        }
+        for task in self.tasks.iter() {
+            for (order, child) in task.sub_tasks.iter().enumerate() {
+                dot.push_str(&format!("    t{} -> t{} [label=\"{}\"];\n", task.index, child, order + 1));
+            }
+            if let Some(target) = task.target {
+                dot.push_str(&format!("    t{} -> t{} [style=dashed, constraint=false];\n", task.index, target));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Mermaid flowchart, renders straight in markdown on most code review sites
+    pub fn to_mermaid(&self, planner: Option<&Planner<C>>) -> String {
+        let mut mermaid = "flowchart TD\n".to_owned();
+        mermaid.push_str("    classDef sequence fill:#fff,stroke:#333\n");
+        mermaid.push_str("    classDef selector fill:#fff,stroke:#36c\n");
+        mermaid.push_str("    classDef utility fill:#fff,stroke:#639\n");
+        mermaid.push_str("    classDef primitive fill:#fff,stroke:#393\n");
+        mermaid.push_str("    classDef pause fill:#eee,stroke:#999\n");
+        mermaid.push_str("    classDef reference fill:#fff,stroke:#999,stroke-dasharray:4\n");
+        mermaid.push_str("    classDef slot fill:#fff,stroke:#c63,stroke-dasharray:4\n");
+        mermaid.push_str("    classDef repeat fill:#fff,stroke:#333,stroke-width:3px\n");
+        mermaid.push_str("    classDef random fill:#fff,stroke:#36c,stroke-dasharray:2\n");
+        mermaid.push_str("    classDef planned fill:#add8e6\n");
+        mermaid.push_str("    classDef current fill:#ffd700\n");
+        for task in self.tasks.iter() {
+            let text = escape_mermaid(&label(task, "<br/>"));
+            let (node, class) = match task.task_type {
+                TaskType::Sequence => (format!("[\"{}\"]", text), "sequence"),
+                TaskType::Selector => (format!("{{\"{}\"}}", text), "selector"),
+                TaskType::UtilitySelector => (format!("{{{{\"{}\"}}}}", text), "utility"),
+                TaskType::Primitive => (format!("([\"{}\"])", text), "primitive"),
+                TaskType::Pause => (format!("[[\"{}\"]]", text), "pause"),
+                TaskType::Reference => (format!(">\"{}\"]", text), "reference"),
+                TaskType::Slot => (format!("[/\"{}\"/]", text), "slot"),
+                TaskType::Repeat => (format!("(\"{}\")", text), "repeat"),
+                TaskType::RandomSelector => (format!("{{\"{}\"}}", text), "random"),
+            };
+            mermaid.push_str(&format!("    t{}{}\n", task.index, node));
+            let class = match highlight(task.index, planner) {
+                Highlight::Current => "current",
+                Highlight::Planned => "planned",
+                Highlight::None => class,
+            };
+            mermaid.push_str(&format!("    class t{} {}\n", task.index, class));
+        }
+        for task in self.tasks.iter() {
+            for (order, child) in task.sub_tasks.iter().enumerate() {
+                mermaid.push_str(&format!("    t{} -->|{}| t{}\n", task.index, order + 1, child));
+            }
+            if let Some(target) = task.target {
+                mermaid.push_str(&format!("    t{} -.-> t{}\n", task.index, target));
+            }
+        }
+        mermaid
+    }
+}
+
+enum Highlight {
+    Current,
+    Planned,
+    None,
+}
+
+fn highlight<C: Context>(index: usize, planner: Option<&Planner<C>>) -> Highlight {
+    match planner {
+        // tasks from the planner's slots aren't in this behaviour
+        Some(planner) if planner.current_task == Some(index) && planner.current_slot.is_none() => Highlight::Current,
+        Some(planner) if planner.plan.iter().any(|step| step.task == index && step.slot.is_none()) => Highlight::Planned,
+        _ => Highlight::None,
+    }
+}
+
+// name and type, then one line each for the score (~), conditions (?), effects (!) and
+// the operator (>)
+fn label<C: Context>(task: &Task<C>, line_break: &str) -> String {
+    let mut label = match task.task_type {
+        TaskType::Repeat => format!("{} (Repeat x{})", task.name, task.repeat),
+        _ => format!("{} ({:?})", task.name, task.task_type),
+    };
+    if let Some((name, _)) = &task.score {
+        label.push_str(&format!("{}~ {}", line_break, name));
+    }
+    if task.conditions.len() > 0 {
+        let names: Vec<&str> = task.conditions.iter().map(|(name, _)| name.as_str()).collect();
+        label.push_str(&format!("{}? {}", line_break, names.join(", ")));
+    }
+    if task.effects.len() > 0 {
+        let names: Vec<&str> = task.effects.iter().map(|(name, _, _)| name.as_str()).collect();
+        label.push_str(&format!("{}! {}", line_break, names.join(", ")));
+    }
+    if task.operator.is_some() {
+        label.push_str(&format!("{}> {}", line_break, task.operator_name));
+    }
+    label
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_behaviour() -> Behaviour<BeingContext> {
+        let mut builder = BehaviourBuilder::new("test");
+        builder
+            .selector("root")
+                .sequence("Study")
+                    .condition("has a \"book\"", |ctx: &BeingContext| true)
+                    .primitive("Sit")
+                        .effect("sitting", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {})
+                        .do_action("sit", |ctx: &mut BeingContext| TaskStatus::Success)
+                    .end()
+                    .primitive("Read")
+                        .do_action("read", |ctx: &mut BeingContext| TaskStatus::Continue)
+                    .end()
+                .end()
+            .end();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn dot_has_styled_labelled_nodes_and_highlights() {
+        let b = test_behaviour();
+        let mut p = Planner::default();
+        let mut ctx = BeingContext::default();
+        p.tick(&b, &mut ctx, 0.0);
+        assert!(b.to_dot(Some(&p)).contains("fillcolor=lightblue"));
+        p.tick(&b, &mut ctx, 0.0);
+
+        let dot = b.to_dot(Some(&p));
+        assert!(dot.starts_with("digraph \"test\" {"));
+        assert!(dot.contains("t0 [label=\"root (Selector)\", shape=diamond, fillcolor=white]"));
+        assert!(dot.contains("t1 [label=\"Study (Sequence)\\n? has a \\\"book\\\"\", shape=box"));
+        assert!(dot.contains("t2 [label=\"Sit (Primitive)\\n! sitting\\n> sit\", shape=ellipse, fillcolor=white]"));
+        assert!(dot.contains("t3 [label=\"Read (Primitive)\\n> read\", shape=ellipse, fillcolor=gold]"));
+        assert!(dot.contains("t1 -> t3 [label=\"2\"]"));
+    }
+
+    #[test]
+    fn mermaid_has_classes_and_edges() {
+        let b = test_behaviour();
+        let mermaid = b.to_mermaid(None);
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("t0{\"root (Selector)\"}"));
+        assert!(mermaid.contains("t1[\"Study (Sequence)<br/>? has a #quot;book#quot;\"]"));
+        assert!(mermaid.contains("class t2 primitive"));
+        assert!(mermaid.contains("t1 -->|1| t2"));
+    }
+
+    #[test]
+    fn tree_indents_by_depth() {
+        let b = test_behaviour();
+        assert_eq!(b.to_tree(), "root\n  Study\n    Sit\n    Read\n");
+    }
+}
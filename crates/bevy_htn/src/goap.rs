@@ -0,0 +1,274 @@
+use crate::prelude::*;
+use crate::task::*;
+use crate::cache::fingerprint;
+use bevy_ecs::component::Component;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+// Goal oriented planning over the same tasks, conditions and effects as the HTN
+// planner. The behaviour's primitive tasks are the actions, and an A* search finds
+// the cheapest sequence of them that makes the goal valid. The plan it finds is run
+// by a Planner, exactly as if it had decomposed it.
+
+pub const DEFAULT_MAX_NODES: usize = 1000;
+
+/// What a GoapPlanner plans for
+pub struct Goal<C> {
+    pub name: String,
+    condition: Box<dyn Condition<C>>,
+    heuristic: Option<Box<dyn Fn(&C) -> f32 + Sync + Send>>,
+}
+
+impl<C: Context> Goal<C> {
+    pub fn new<K: Condition<C> + 'static>(name: &str, condition: K) -> Self {
+        Goal {
+            name: name.to_owned(),
+            condition: Box::new(condition),
+            heuristic: None,
+        }
+    }
+
+    /// Estimates what's left to pay to reach the goal from a state, to guide the search.
+    /// It must never guess more than the real cost or the plan might not be the cheapest.
+    /// Without one every state is guessed at 0, which is slower but always right.
+    pub fn heuristic(mut self, heuristic: impl Fn(&C) -> f32 + Sync + Send + 'static) -> Self {
+        self.heuristic = Some(Box::new(heuristic));
+        self
+    }
+
+    pub fn is_met(&self, ctx: &C) -> bool {
+        self.condition.is_valid(ctx)
+    }
+
+    fn estimate(&self, ctx: &C) -> f32 {
+        self.heuristic.as_ref().map_or(0.0, |heuristic| heuristic(ctx))
+    }
+}
+
+/// Plans toward a goal with the primitive tasks of whatever behaviour it's ticked with,
+/// using their costs (see BehaviourBuilder::cost). Bindings, cooldowns and the rest of
+/// the behaviour's structure only matter to the HTN planner. The HtnPlugin ticks it on
+/// agents that have one, with their behaviour from the BehaviourRegistry, in place of
+/// a Planner.
+#[derive(Component)]
+pub struct GoapPlanner<C>
+    where C: Context
+{
+    /// How many states a search may look at before giving up
+    pub max_nodes: usize,
+    runner: Planner<C>,
+    goal: Option<Goal<C>>,
+    nodes: usize,
+    searches: usize,
+    last_search: Option<DecompositionStatus>,
+}
+
+impl<C: Context> Default for GoapPlanner<C> {
+    fn default() -> Self {
+        GoapPlanner {
+            max_nodes: DEFAULT_MAX_NODES,
+            runner: Planner::default(),
+            goal: None,
+            nodes: 0,
+            searches: 0,
+            last_search: None,
+        }
+    }
+}
+
+impl<C: Context> GoapPlanner<C> {
+    pub fn new(max_nodes: usize) -> Self {
+        GoapPlanner {
+            max_nodes: max_nodes,
+            ..Default::default()
+        }
+    }
+
+    /// Plans for this goal from the next tick, dropping the plan for the last one
+    pub fn set_goal(&mut self, ctx: &mut C, goal: Goal<C>) {
+        self.goal = Some(goal);
+        ctx.state_mut().dirty = true;
+    }
+
+    pub fn goal(&self) -> Option<&Goal<C>> {
+        self.goal.as_ref()
+    }
+
+    /// Like Planner::tick. Searches when the plan runs out, the one running failed or
+    /// the context is dirty, then runs the current task. A search that fails keeps
+    /// whatever was running. One that leaves nothing to do, as the goal's met or can't
+    /// be, isn't tried again until something in the context changes.
+    pub fn tick(&mut self, behaviour: &Behaviour<C>, ctx: &mut C, delta: f32) {
+        let idle = self.runner.watch_everything;
+        if self.runner.prepare(behaviour, ctx, delta)
+        && self.goal.is_some()
+        && (!idle || ctx.state().dirty) {
+            let (plan, status) = self.find_plan(behaviour, ctx);
+            match status {
+                DecompositionStatus::Succeeded => self.runner.set_plan(ctx, behaviour, plan),
+                _ => ctx.state_mut().dirty = false,
+            }
+            self.runner.watch_everything = !self.runner.has_plan() && self.runner.current_task().is_none();
+        }
+        self.runner.run(behaviour, ctx, false);
+    }
+
+    /// The cheapest plan that meets the goal, Failed if there's no goal or no plan
+    /// within max_nodes. An empty plan if the goal is already met.
+    pub fn find_plan(&mut self, behaviour: &Behaviour<C>, ctx: &mut C) -> (Plan, DecompositionStatus) {
+        let goal = match self.goal.take() {
+            Some(goal) => goal,
+            None => return (Plan::default(), DecompositionStatus::Failed),
+        };
+        let (path, nodes) = search(behaviour, ctx, &goal, self.max_nodes);
+        self.goal = Some(goal);
+        self.nodes = nodes;
+        self.searches += 1;
+
+        let status = match path {
+            Some(path) => {
+                // permanent effects are kept once the plan's found, as with decomposition
+                ctx.state_mut().exec_state = ExecutionState::Planning;
+                ctx.state_mut().begin_transaction();
+                for task in path.iter() {
+                    behaviour.get_task(*task).apply_effects(ctx);
+                }
+                ctx.state_mut().commit_permanent_only();
+                ctx.state_mut().exec_state = ExecutionState::Executing;
+                let plan = path.into_iter()
+                    .map(|task| PlanStep { task: task, slot: None, params: Params::default() })
+                    .collect();
+                (plan, DecompositionStatus::Succeeded)
+            },
+            None => (Plan::default(), DecompositionStatus::Failed),
+        };
+        self.last_search = Some(status.1);
+        status
+    }
+
+    /// How many states the last search looked at
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    /// How many times it's searched
+    pub fn searches(&self) -> usize {
+        self.searches
+    }
+
+    pub fn last_search(&self) -> Option<DecompositionStatus> {
+        self.last_search
+    }
+
+    /// The planner running the plan, for its current task, plan and status
+    pub fn planner(&self) -> &Planner<C> {
+        &self.runner
+    }
+
+    pub fn reset(&mut self, ctx: &mut C) {
+        self.runner.reset(ctx);
+    }
+}
+
+// a plan being searched, cheapest estimate first out of the heap
+struct Node {
+    estimate: f32,
+    cost: f32,
+    // earlier nodes first on equal estimates, so searches are repeatable
+    order: usize,
+    path: Vec<usize>,
+    // where its state is in the seen states
+    state: (u64, usize),
+}
+
+impl PartialEq for Node {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Node {}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate).then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+// A* from the context as it is. Every node's state is rebuilt by replaying its path in
+// a transaction, and states are told apart by what the path changed.
+fn search<C: Context>(behaviour: &Behaviour<C>, ctx: &mut C, goal: &Goal<C>, max_nodes: usize) -> (Option<Vec<usize>>, usize) {
+    let actions: Vec<&Task<C>> = behaviour.tasks.iter()
+        .filter(|task| task.task_type == TaskType::Primitive)
+        .collect();
+    let mut open = BinaryHeap::new();
+    // the cheapest way found to each state, by the fingerprint of its changes
+    let mut seen: HashMap<u64, Vec<(Vec<(KeyId, Option<Variant>)>, f32)>> = HashMap::new();
+    let start = fingerprint(&[]);
+    seen.insert(start, vec![(vec![], 0.0)]);
+    let mut order = 0;
+    let mut nodes = 0;
+    open.push(Node { estimate: goal.estimate(ctx), cost: 0.0, order: order, path: vec![], state: (start, 0) });
+
+    ctx.state_mut().exec_state = ExecutionState::Planning;
+    let mut found = None;
+    while let Some(node) = open.pop() {
+        // a cheaper way to the same state was found after this one was queued
+        let (hash, index) = node.state;
+        if node.cost > seen[&hash][index].1 {
+            continue;
+        }
+        if nodes == max_nodes {
+            break;
+        }
+        nodes += 1;
+
+        ctx.state_mut().begin_transaction();
+        for task in node.path.iter() {
+            behaviour.get_task(*task).apply_effects(ctx);
+        }
+        if goal.is_met(ctx) {
+            ctx.state_mut().rollback_transaction();
+            found = Some(node.path);
+            break;
+        }
+        for action in actions.iter() {
+            if !action.is_valid(ctx) {
+                continue;
+            }
+            ctx.state_mut().begin_transaction();
+            action.apply_effects(ctx);
+            let state = ctx.state().transaction_changes();
+            let estimate = goal.estimate(ctx);
+            ctx.state_mut().rollback_transaction();
+
+            let cost = node.cost + action.cost;
+            let hash = fingerprint(&state);
+            let same = seen.entry(hash).or_default();
+            let index = match same.iter().position(|(seen_state, _)| *seen_state == state) {
+                Some(index) if same[index].1 <= cost => continue,
+                Some(index) => {
+                    same[index].1 = cost;
+                    index
+                },
+                None => {
+                    same.push((state, cost));
+                    same.len() - 1
+                },
+            };
+            let mut path = node.path.clone();
+            path.push(action.index);
+            order += 1;
+            open.push(Node { estimate: cost + estimate, cost: cost, order: order, path: path, state: (hash, index) });
+        }
+        ctx.state_mut().rollback_transaction();
+    }
+    ctx.state_mut().exec_state = ExecutionState::Executing;
+    (found, nodes)
+}
@@ -1,5 +1,8 @@
 use crate::prelude::*;
-use std::collections::VecDeque;
+use bevy_ecs::entity::Entity;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
 
 pub trait Condition<C>: Sync + Send
 where C: Context
@@ -17,12 +20,31 @@ where
     }
 }
 
+/// Valid when the wrapped condition isn't, e.g. `.condition("awake", Not(asleep))`
+pub struct Not<K>(pub K);
+
+impl<C, K> Condition<C> for Not<K>
+where
+    C: Context,
+    K: Condition<C>,
+{
+    fn is_valid(&self, ctx: &C) -> bool {
+        !self.0.is_valid(ctx)
+    }
+}
+
+/// The lifecycle of a primitive task's operator, as driven by the planner: on_start
+/// when the task becomes current, update every tick until it stops continuing, then
+/// on_stop with how it ended. If a replan or a failed exec condition takes the task
+/// away first, on_interrupt is called instead of on_stop.
 pub trait Operator<C>: Sync + Send
 where
     C: Context
 {
+    fn on_start(&self, _ctx: &mut C) {}
     fn update(&self, ctx: &mut C) -> TaskStatus;
-    fn stop(&self, ctx: &mut C);
+    fn on_stop(&self, _ctx: &mut C, _status: TaskStatus) {}
+    fn on_interrupt(&self, _ctx: &mut C) {}
 }
 
 impl<C, F> Operator<C> for F 
@@ -33,12 +55,146 @@ where
     fn update(&self, ctx: &mut C) -> TaskStatus {
         self(ctx)
     }
+}
+
+type Hook<C> = Box<dyn Fn(&mut C) + Sync + Send>;
+
+/// An operator made of closures, for when a plain update closure isn't enough, e.g.
+/// `FnOperator::new(walk).on_interrupt(stop_walking)`
+pub struct FnOperator<C> {
+    start: Option<Hook<C>>,
+    update: Box<dyn Fn(&mut C) -> TaskStatus + Sync + Send>,
+    stop: Option<Box<dyn Fn(&mut C, TaskStatus) + Sync + Send>>,
+    interrupt: Option<Hook<C>>,
+}
+
+impl<C: Context> FnOperator<C> {
+    pub fn new(update: impl Fn(&mut C) -> TaskStatus + Sync + Send + 'static) -> Self {
+        FnOperator {
+            start: None,
+            update: Box::new(update),
+            stop: None,
+            interrupt: None,
+        }
+    }
+
+    pub fn on_start(mut self, start: impl Fn(&mut C) + Sync + Send + 'static) -> Self {
+        self.start = Some(Box::new(start));
+        self
+    }
+
+    pub fn on_stop(mut self, stop: impl Fn(&mut C, TaskStatus) + Sync + Send + 'static) -> Self {
+        self.stop = Some(Box::new(stop));
+        self
+    }
+
+    pub fn on_interrupt(mut self, interrupt: impl Fn(&mut C) + Sync + Send + 'static) -> Self {
+        self.interrupt = Some(Box::new(interrupt));
+        self
+    }
+}
+
+impl<C: Context> Operator<C> for FnOperator<C> {
+    fn on_start(&self, ctx: &mut C) {
+        if let Some(start) = self.start.as_ref() {
+            start(ctx);
+        }
+    }
+
+    fn update(&self, ctx: &mut C) -> TaskStatus {
+        (self.update)(ctx)
+    }
+
+    fn on_stop(&self, ctx: &mut C, status: TaskStatus) {
+        if let Some(stop) = self.stop.as_ref() {
+            stop(ctx, status);
+        }
+    }
+
+    fn on_interrupt(&self, ctx: &mut C) {
+        if let Some(interrupt) = self.interrupt.as_ref() {
+            interrupt(ctx);
+        }
+    }
+}
+
+/// Does nothing until its task has been running for this many seconds of planner
+/// time, then succeeds
+pub struct Wait {
+    pub seconds: f32,
+}
+
+impl<C: Context> Operator<C> for Wait {
+    fn update(&self, ctx: &mut C) -> TaskStatus {
+        match ctx.state().task_time() >= self.seconds {
+            true => TaskStatus::Success,
+            false => TaskStatus::Continue,
+        }
+    }
+}
+
+/// How much a utility selector wants one of its sub-tasks, higher is better
+pub trait Score<C>: Sync + Send
+where
+    C: Context
+{
+    fn score(&self, ctx: &C) -> f32;
+}
+
+impl<C, F> Score<C> for F
+where
+    C: Context,
+    F: Fn(&C) -> f32 + Sync + Send,
+{
+    fn score(&self, ctx: &C) -> f32 {
+        self(ctx)
+    }
+}
+
+/// Maps a number onto 0..1 for scoring, e.g. how much hunger matters
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Curve {
+    /// 0 at from, 1 at to. from can be bigger than to, for scores that fall off.
+    Linear { from: f32, to: f32 },
+    /// Linear raised to a power, so it rises slowly then quickly (or the reverse below 1)
+    Power { from: f32, to: f32, power: f32 },
+    /// An S, 0.5 at midpoint
+    Logistic { midpoint: f32, steepness: f32 },
+}
+
+impl Curve {
+    pub fn evaluate(&self, x: f32) -> f32 {
+        match *self {
+            Curve::Linear { from, to } => ((x - from) / (to - from)).clamp(0.0, 1.0),
+            Curve::Power { from, to, power } => ((x - from) / (to - from)).clamp(0.0, 1.0).powf(power),
+            Curve::Logistic { midpoint, steepness } => 1.0 / (1.0 + (-steepness * (x - midpoint)).exp()),
+        }
+    }
+}
 
-    fn stop(&self, ctx: &mut C) {
+/// Scores a context key by running it through a curve. 0 if it's missing or not a number.
+pub struct CurveScore {
+    pub key: KeyId,
+    pub curve: Curve,
+}
 
+impl<C: Context> Score<C> for CurveScore {
+    fn score(&self, ctx: &C) -> f32 {
+        ctx.get(&self.key).and_then(|value| value.as_f32()).map_or(0.0, |x| self.curve.evaluate(x))
     }
 }
 
+/// When an effect gets applied to the context
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EffectType {
+    /// Only while planning - thrown away once the plan is found
+    PlanOnly,
+    /// While planning (then thrown away), and again for real when the task succeeds
+    PlanAndExecute,
+    /// While planning, and kept once the plan is found
+    Permanent,
+}
+
 pub trait Effect<C>: Sync + Send
 where
     C: Context
@@ -56,4 +212,101 @@ where
     }
 }
 
-pub type Plan = VecDeque<usize>;
\ No newline at end of file
+/// How a blackboard value is compared against the expected one.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn accepts(&self, ordering: Ordering) -> bool {
+        use Comparison::*;
+        match self {
+            Equal => ordering == Ordering::Equal,
+            NotEqual => ordering != Ordering::Equal,
+            Less => ordering == Ordering::Less,
+            LessOrEqual => ordering != Ordering::Greater,
+            Greater => ordering == Ordering::Greater,
+            GreaterOrEqual => ordering != Ordering::Less,
+        }
+    }
+}
+
+/// Condition over a single key in the context state - missing keys or values that
+/// can't be compared are always invalid.
+pub struct CompareCondition {
+    pub key: KeyId,
+    pub comparison: Comparison,
+    pub value: Variant,
+}
+
+impl<C: Context> Condition<C> for CompareCondition {
+    fn is_valid(&self, ctx: &C) -> bool {
+        use Comparison::*;
+        match self.comparison {
+            // equality works for every variant, not just the ordered ones
+            Equal => ctx.state().test_value(&self.key, &self.value).unwrap_or(false),
+            NotEqual => ctx.state().test_value(&self.key, &self.value).map_or(false, |eq| !eq),
+            _ => ctx.state().compare(&self.key, &self.value)
+                .map_or(false, |ordering| self.comparison.accepts(ordering)),
+        }
+    }
+}
+
+/// Valid if min <= value <= max.
+pub struct InRangeCondition {
+    pub key: KeyId,
+    pub min: Variant,
+    pub max: Variant,
+}
+
+impl<C: Context> Condition<C> for InRangeCondition {
+    fn is_valid(&self, ctx: &C) -> bool {
+        let state = ctx.state();
+        let above_min = state.compare(&self.key, &self.min).map_or(false, |o| o != Ordering::Less);
+        let below_max = state.compare(&self.key, &self.max).map_or(false, |o| o != Ordering::Greater);
+        above_min && below_max
+    }
+}
+
+/// Valid if the key holds this entity, or a list of entities containing it.
+pub struct ContainsEntityCondition {
+    pub key: KeyId,
+    pub entity: Entity,
+}
+
+impl<C: Context> Condition<C> for ContainsEntityCondition {
+    fn is_valid(&self, ctx: &C) -> bool {
+        ctx.state().contains_entity(&self.key, self.entity).unwrap_or(false)
+    }
+}
+
+/// Where a task parameter gets its value from
+#[derive(PartialEq, Clone, Debug)]
+pub enum Binding {
+    Value(Variant),
+    /// Whatever's at this context key when the task is planned
+    Key(KeyId),
+}
+
+/// Task parameter values, by name
+pub type Params = BTreeMap<String, Variant>;
+
+/// A primitive task in a plan, with the parameter values it was planned with
+#[derive(PartialEq, Clone, Debug)]
+pub struct PlanStep {
+    pub task: usize,
+    /// The slot task whose behaviour the task is from, None if it's from the planner's own
+    pub slot: Option<usize>,
+    pub params: Params,
+}
+
+/// Sub-behaviours filling a planner's slots, by slot name
+pub type Slots<C> = HashMap<String, Arc<Behaviour<C>>>;
+
+pub type Plan = VecDeque<PlanStep>;
\ No newline at end of file
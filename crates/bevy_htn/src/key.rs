@@ -0,0 +1,175 @@
+use bevy_ecs::entity::Entity;
+use glam::Vec2;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock, RwLock};
+use crate::context::Variant;
+
+// Blackboard keys are interned to small integers, so contexts can keep their values
+// in a plain Vec and nothing on the planning path hashes, locks or allocates. Names are
+// only turned into ids where a key is declared or a behaviour is built, never by the
+// lookups themselves.
+
+/// An interned key name. Every context shares the same ids.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct KeyId(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<Arc<str>, KeyId>,
+    names: Vec<Arc<str>>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| RwLock::new(Interner::default()))
+}
+
+impl KeyId {
+    /// The id for this name, giving it one if it hasn't got one yet. It takes a lock,
+    /// so intern once and keep the id - or declare a Key - rather than every lookup.
+    pub fn intern(name: &str) -> KeyId {
+        if let Some(id) = KeyId::find(name) {
+            return id;
+        }
+        let mut interner = interner().write().unwrap();
+        // someone else may have got there between the locks
+        if let Some(id) = interner.ids.get(name) {
+            return *id;
+        }
+        let name: Arc<str> = Arc::from(name);
+        let id = KeyId(interner.names.len() as u32);
+        interner.names.push(name.clone());
+        interner.ids.insert(name, id);
+        id
+    }
+
+    /// The id for this name, if it has one
+    pub fn find(name: &str) -> Option<KeyId> {
+        interner().read().unwrap().ids.get(name).copied()
+    }
+
+    // how many names have ids, which are 0 up to this
+    pub(crate) fn count() -> usize {
+        interner().read().unwrap().names.len()
+    }
+
+    pub fn name(&self) -> Arc<str> {
+        interner().read().unwrap().names[self.0 as usize].clone()
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.0 as usize
+    }
+
+    pub(crate) fn from_index(index: usize) -> KeyId {
+        KeyId(index as u32)
+    }
+}
+
+impl fmt::Debug for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyId({:?})", self.name())
+    }
+}
+
+/// A key whose values are always a T, declared once so a typo is a compile error
+/// rather than a condition that's quietly never valid, e.g.
+/// `static HUNGER: Key<i32> = Key::new("hunger");` then `ctx.value(&HUNGER)`
+pub struct Key<T> {
+    name: &'static str,
+    id: OnceLock<KeyId>,
+    pd: PhantomData<fn() -> T>,
+}
+
+impl<T: KeyType> Key<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Key {
+            name: name,
+            id: OnceLock::new(),
+            pd: PhantomData,
+        }
+    }
+
+    /// Interned the first time it's asked for
+    pub fn id(&self) -> KeyId {
+        *self.id.get_or_init(|| KeyId::intern(self.name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// A key a context can be asked about - a KeyId or a Key. A name has to be made into
+/// one of those first, with KeyId::intern.
+pub trait AsKey {
+    fn key_id(&self) -> KeyId;
+}
+
+impl AsKey for KeyId {
+    fn key_id(&self) -> KeyId {
+        *self
+    }
+}
+
+impl<T: KeyType> AsKey for Key<T> {
+    fn key_id(&self) -> KeyId {
+        self.id()
+    }
+}
+
+impl<K: AsKey + ?Sized> AsKey for &K {
+    fn key_id(&self) -> KeyId {
+        (**self).key_id()
+    }
+}
+
+/// The types a Key can hold, one per Variant
+pub trait KeyType: Sized {
+    fn from_variant(variant: &Variant) -> Option<&Self>;
+    fn into_variant(self) -> Variant;
+}
+
+macro_rules! key_type {
+    ($type:ty, $variant:ident) => {
+        impl KeyType for $type {
+            fn from_variant(variant: &Variant) -> Option<&Self> {
+                match variant {
+                    Variant::$variant(value) => Some(value),
+                    _ => None,
+                }
+            }
+
+            fn into_variant(self) -> Variant {
+                Variant::$variant(self)
+            }
+        }
+    };
+}
+
+key_type!(Entity, Entity);
+key_type!(Vec<Entity>, Entities);
+key_type!(Vec2, Location);
+key_type!(bool, Bool);
+key_type!(i32, Int32);
+key_type!(f32, Float);
+key_type!(String, Str);
+key_type!(BTreeMap<String, Variant>, Map);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_intern_to_the_same_id() {
+        static HUNGER: Key<i32> = Key::new("key test hunger");
+        assert_eq!(KeyId::find("key test never used"), None);
+        let id = KeyId::intern("key test hunger");
+        assert_eq!(HUNGER.id(), id);
+        assert_eq!(KeyId::find("key test hunger"), Some(id));
+        assert_eq!(&*id.name(), "key test hunger");
+        assert_ne!(KeyId::intern("key test tired"), id);
+    }
+}
@@ -1,15 +1,33 @@
+// so derive(Context) can name the crate from inside it too
+extern crate self as bevy_htn;
+
+pub mod asset;
 pub mod behaviour;
+pub mod cache;
 pub mod context;
+pub mod export;
+pub mod goap;
 pub mod htn;
+pub mod key;
 pub mod planner;
+pub mod plugin;
+pub mod sensor;
 pub mod task;
+pub mod trace;
 
 pub mod prelude {
     pub use crate::{
-        behaviour::{Behaviour, BehaviourBuilder},
+        asset::{HtnAssetPlugin, BehaviourLibrary, BehaviourLoadError, BehaviourDef, TaskDef},
+        behaviour::{Behaviour, BehaviourBuilder, BehaviourError},
         planner::Planner,
-        task::TaskStatus,
+        cache::{PlanCache, CacheStats},
+        goap::{GoapPlanner, Goal},
+        sensor::{AddSensor, Sensor},
+        plugin::{HtnPlugin, HtnSystem, HtnSettings, HtnClock, PlanBudget, BehaviourRegistry, BehaviourName, FromBehaviourAsset, htn_enabled},
+        task::{TaskStatus, DecompositionStatus, DecompositionMode},
         htn::*,
+        key::{Key, KeyId, AsKey, KeyType},
+        trace::{DecompositionTrace, TraceEvent},
         context::{BeingContext, Variant, ExecutionState, Context, ContextState,},
     };
 }
\ No newline at end of file
@@ -1,30 +1,126 @@
 use crate::prelude::*;
 use crate::task::*;
+use crate::cache::ReadSet;
 use std::collections::VecDeque;
+use bevy_ecs::component::Component;
+use bevy_utils::tracing::warn;
 use std::marker::PhantomData;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::mem;
+use std::sync::Arc;
 
-#[derive(Default,)]
+#[derive(Component)]
 pub struct Planner<C> 
     where C: Context
 {
-    plan: Plan,
-    current_task: Option<usize>,
+    pub(crate) plan: Plan,
+    pub(crate) current_task: Option<usize>,
+    // the slot task the current task's behaviour fills, if it's from one
+    pub(crate) current_slot: Option<usize>,
+    // the current task's operator, so it can still be interrupted once its behaviour's gone
+    running: Option<Arc<dyn Operator<C>>>,
     last_status: TaskStatus,
+    last_decomposition: Option<DecompositionStatus>,
+    trace: Option<DecompositionTrace>,
+    // lent to the context while decomposing, like the trace
+    rng: Option<StdRng>,
+    // id of the behaviour the plan came from
+    behaviour_id: Option<usize>,
+    // tasks with dependencies the last plan was decided on - the ones in it, and the
+    // higher priority ones that were turned down
+    watched: Vec<(Option<usize>, usize)>,
+    // when a cooldown that kept something out of the plan is over
+    cooldown_wake: Option<f64>,
+    // the frame the HtnPlugin first turned down its wish to plan, for fair queueing
+    pub(crate) waiting_since: Option<u64>,
+    // how many steps it took to plan last time, what the HtnPlugin expects it to take next
+    pub(crate) last_steps: Option<usize>,
+    // any change asks for a plan, not just ones to what the plan was decided on - for a
+    // GoapPlanner with nothing to do, which doesn't know what its search looked at
+    pub(crate) watch_everything: bool,
+    slots: Slots<C>,
+    // slots filled or emptied since the last tick, with what used to be in them
+    slot_changes: Vec<(String, Option<Arc<Behaviour<C>>>)>,
     pd: PhantomData<C>,
 }
 
+// not derived, so C doesn't have to be Default
+impl<C: Context> Default for Planner<C> {
+    fn default() -> Self {
+        Planner {
+            plan: Plan::default(),
+            current_task: None,
+            current_slot: None,
+            running: None,
+            last_status: TaskStatus::default(),
+            last_decomposition: None,
+            trace: None,
+            rng: Some(StdRng::from_entropy()),
+            behaviour_id: None,
+            watched: vec![],
+            cooldown_wake: None,
+            waiting_since: None,
+            last_steps: None,
+            watch_everything: false,
+            slots: Slots::default(),
+            slot_changes: vec![],
+            pd: PhantomData::default(),
+        }
+    }
+}
+
 impl<C> Planner<C> 
     where C: Context
 {
-    pub fn tick(&mut self, behaviour: &Behaviour<C>, ctx: &mut C) {
+    /// Plans if need be and runs the current task. delta is the seconds since the
+    /// last tick, the clock that waits, timeouts and cooldowns go by.
+    pub fn tick(&mut self, behaviour: &Behaviour<C>, ctx: &mut C, delta: f32) {
+        self.prepare(behaviour, ctx, delta);
+        self.run(behaviour, ctx, true);
+    }
+
+    /// The first half of a tick: moves the clock on and catches up with anything that
+    /// changed since the last one. True if the planner then wants to plan.
+    pub fn prepare(&mut self, behaviour: &Behaviour<C>, ctx: &mut C, delta: f32) -> bool {
+        ctx.state_mut().delta = delta;
+        ctx.state_mut().now += delta as f64;
+
+        if self.behaviour_id != Some(behaviour.id) {
+            self.reset(ctx);
+            self.behaviour_id = Some(behaviour.id);
+        }
+        self.apply_slot_changes(ctx, behaviour);
+        ctx.sync_keys();
 
+        // only changes the plan was decided on are worth replanning for
+        if self.watched_changed(ctx, behaviour)
+        || self.watch_everything && ctx.state().changed().next().is_some() {
+            ctx.state_mut().dirty = true;
+        }
+        ctx.state_mut().clear_changes();
+        if self.cooldown_wake.map_or(false, |wake| ctx.state().now >= wake) {
+            self.cooldown_wake = None;
+            ctx.state_mut().dirty = true;
+        }
+        self.wants_plan(ctx)
+    }
+
+    /// The second half of a tick, after prepare: plans if it wants to and may, then
+    /// runs the current task. A planner that isn't allowed to plan carries on with
+    /// what it's doing, and still wants to plan next time. Returns how many tasks
+    /// planning visited, None if it didn't plan.
+    pub fn run(&mut self, behaviour: &Behaviour<C>, ctx: &mut C, may_plan: bool) -> Option<usize> {
         let mut status = DecompositionStatus::Failed;
         let mut replacing = false;
+        let mut steps = None;
+        let deferred = self.wants_plan(ctx) && !may_plan;
 
         // get plan if we need it
-        if !self.has_plan() && self.current_task.is_none() || ctx.state_mut().dirty {
+        if self.wants_plan(ctx) && may_plan {
             replacing = self.plan.len() > 0;
             status = self.find_plan(ctx, behaviour);
+            steps = Some(ctx.state().steps);
         }
 
         // get current task from plan if needed
@@ -34,7 +130,8 @@ impl<C> Planner<C>
 
         // handle the current task
         if let Some(task) = self.current_task {
-            let task_ref = behaviour.get_task(task);
+            let filling = self.filling(behaviour, self.current_slot);
+            let task_ref = filling.as_deref().unwrap_or(behaviour).get_task(task);
             if task_ref.get_type() == TaskType::Primitive {
                 self.handle_task(ctx, task_ref);
             }
@@ -44,6 +141,7 @@ impl<C> Planner<C>
         if !self.has_plan()
         && self.current_task.is_none()
         && !replacing
+        && !deferred
         && (
             status == DecompositionStatus::Failed 
             || status == DecompositionStatus::Rejected
@@ -51,6 +149,11 @@ impl<C> Planner<C>
         {
             self.last_status = TaskStatus::Failure;
         }
+        steps
+    }
+
+    fn wants_plan(&self, ctx: &C) -> bool {
+        !self.has_plan() && self.current_task.is_none() || ctx.state().dirty
     }
 
     fn find_plan(&mut self, ctx: &mut C, behaviour: &Behaviour<C>) 
@@ -68,26 +171,83 @@ impl<C> Planner<C>
             ctx.state_mut().dump_into_last_record();
         }
 
-        let plan_status = behaviour.find_plan(ctx);
+        // lend the trace to the context so the decomposition can write to it
+        if let Some(mut trace) = self.trace.take() {
+            trace.clear();
+            ctx.state_mut().trace = Some(trace);
+        }
+        ctx.state_mut().rng = self.rng.take();
+        ctx.state_mut().visited.clear();
+        // planning binds its own parameters, the running task's are put back after
+        let running_params = std::mem::take(&mut ctx.state_mut().params);
+        let running: Vec<PlanStep> = self.current_task
+            .map(|task| PlanStep { task: task, slot: self.current_slot, params: running_params.clone() })
+            .into_iter()
+            .chain(self.plan.iter().cloned())
+            .collect();
+        let cache = behaviour.plan_cache().filter(|_| self.may_cache(ctx));
+        let plan_status = match cache.and_then(|cache| cache.get(ctx.state())) {
+            Some(cached) => {
+                let state = ctx.state_mut();
+                state.record = cached.record;
+                state.visited = cached.visited;
+                state.steps = 0;
+                // cached decompositions never met a cooldown, so what's left from the
+                // last one this context made mustn't be taken as this plan's
+                state.pending_cooldowns.clear();
+                state.cooldown_wake = None;
+                (cached.plan, cached.status)
+            },
+            None => {
+                if cache.is_some() {
+                    ctx.state_mut().reads = Some(ReadSet::new());
+                }
+                let plan_status = behaviour.find_plan_replacing(ctx, &self.slots, &running);
+                // a key first interned while decomposing has no bit, so can't be cached
+                if let Some(reads) = ctx.state_mut().reads.take().and_then(ReadSet::into_keys) {
+                    cache.unwrap().insert(ctx.state(), reads, &plan_status);
+                }
+                plan_status
+            },
+        };
+        ctx.state_mut().params = running_params;
+        if let Some(trace) = ctx.state_mut().trace.take() {
+            self.trace = Some(trace);
+        }
+        self.rng = ctx.state_mut().rng.take();
+        self.last_decomposition = Some(plan_status.1);
         match plan_status.1 {
             DecompositionStatus::Succeeded
             | DecompositionStatus::Partial => {
                 self.plan.clear();
                 self.plan.extend(plan_status.0);
+                self.watched.clear();
+                self.watched.append(&mut ctx.state_mut().visited);
+                self.cooldown_wake = ctx.state().cooldown_wake;
+                let state = ctx.state_mut();
+                for (task, cooldown) in state.pending_cooldowns.drain(..) {
+                    state.cooldowns.insert(task, state.now + cooldown as f64);
+                }
 
-                // are we currently on a primitive task?
-                if let Some(task_index) = self.current_task {
-                    let task = behaviour.get_task(task_index);
-                    if task.task_type != TaskType::Primitive {
-                        task.stop(ctx);
-                        self.current_task = None;
-                    }
+                // the new plan beat the old one, so whatever we were doing gets dropped
+                if let Some(task_index) = self.current_task.take() {
+                    let slot = self.current_slot.take();
+                    let filling = self.filling(behaviour, slot);
+                    filling.as_deref().unwrap_or(behaviour).get_task(task_index).interrupt(ctx);
                 }
 
                 ctx.state_mut().dump_into_last_record();
 
             },
             _ => {
+                // the running plan still stands, but so do the reasons it was rejected
+                self.watched.append(&mut ctx.state_mut().visited);
+                self.watched.sort_unstable();
+                self.watched.dedup();
+                self.cooldown_wake = match (self.cooldown_wake, ctx.state().cooldown_wake) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
                 if last_partial_plan.len() > 0 {
                     ctx.state_mut().paused = true;
                     ctx.state_mut().partial_queue.clear();
@@ -104,34 +264,109 @@ impl<C> Planner<C>
         plan_status.1
     }
 
-    fn get_task_from_plan(&mut self, ctx: &mut C, behaviour: &Behaviour<C>) {
-        let current = self.plan.pop_front().unwrap();
-        self.current_task = Some(current);
-        let task_ref = behaviour.get_task(current);
-        for condition in task_ref.conditions.iter() {
-            if !condition.is_valid(ctx) {
+    // only a fresh plan depends on nothing but the context, and only one from the
+    // planner's own behaviour is the same for every planner. A trace wants to see it made.
+    fn may_cache(&self, ctx: &C) -> bool {
+        !self.has_plan()
+        && self.current_task.is_none()
+        && self.slots.is_empty()
+        && self.trace.is_none()
+        && !ctx.state().paused
+        && ctx.state().last_record.is_empty()
+    }
+
+    fn watched_changed(&self, ctx: &C, behaviour: &Behaviour<C>) -> bool {
+        ctx.state().changed().any(|key| {
+            self.watched.iter().any(|(slot, task)| match slot {
+                None => behaviour.get_task(*task).depends_on(key),
+                Some(_) => self.filling(behaviour, *slot)
+                    .map_or(false, |filling| filling.get_task(*task).depends_on(key)),
+            })
+        })
+    }
+
+    // the behaviour filling a slot. Cloned out so it can be used while the planner changes
+    fn filling(&self, behaviour: &Behaviour<C>, slot: Option<usize>) -> Option<Arc<Behaviour<C>>> {
+        let slot = slot?;
+        self.slots.get(&behaviour.get_task(slot).name).cloned()
+    }
+
+    /// Puts a behaviour in this planner's slot with the given name, replacing whatever
+    /// was there. If the plan uses the slot it's dropped, otherwise the next tick
+    /// replans in case the slot is now the better option.
+    pub fn fill_slot(&mut self, name: &str, behaviour: Arc<Behaviour<C>>) {
+        let old = self.slots.insert(name.to_owned(), behaviour);
+        self.slot_changes.push((name.to_owned(), old));
+    }
+
+    pub fn empty_slot(&mut self, name: &str) {
+        if let Some(old) = self.slots.remove(name) {
+            self.slot_changes.push((name.to_owned(), Some(old)));
+        }
+    }
+
+    pub fn get_slot(&self, name: &str) -> Option<&Arc<Behaviour<C>>> {
+        self.slots.get(name)
+    }
+
+    fn apply_slot_changes(&mut self, ctx: &mut C, behaviour: &Behaviour<C>) {
+        for (name, old) in mem::take(&mut self.slot_changes) {
+            let in_slot = |slot: Option<usize>| {
+                slot.map_or(false, |slot| behaviour.get_task(slot).name == name)
+            };
+            let running = self.current_task.is_some() && in_slot(self.current_slot);
+            if running || self.plan.iter().any(|step| in_slot(step.slot)) {
+                if let Some(task_index) = self.current_task {
+                    // the task has to be stopped by the behaviour it came from
+                    let from = if running { old.as_deref() } else { Some(behaviour) };
+                    if let Some(from) = from {
+                        from.get_task(task_index).interrupt(ctx);
+                    }
+                }
                 self.clear_all(ctx);
             }
+            ctx.state_mut().dirty = true;
         }
     }
 
+    fn get_task_from_plan(&mut self, ctx: &mut C, behaviour: &Behaviour<C>) {
+        let current = self.plan.pop_front().unwrap();
+        self.current_task = Some(current.task);
+        self.current_slot = current.slot;
+        ctx.state_mut().params = current.params;
+        ctx.state_mut().task_started = ctx.state().now;
+        let filling = self.filling(behaviour, current.slot);
+        let task_ref = filling.as_deref().unwrap_or(behaviour).get_task(current.task);
+        if !task_ref.is_valid(ctx) {
+            self.clear_all(ctx);
+            return;
+        }
+        self.running = task_ref.operator.clone();
+        task_ref.start(ctx);
+    }
+
     fn handle_task(&mut self, ctx: &mut C, task: &Task<C>) {
         match &task.operator {
             Some(ref op) => {
-                for exec_cond in task.exec_conditions.iter() {
+                for (_, exec_cond) in task.exec_conditions.iter() {
                     if !exec_cond.is_valid(ctx) {
+                        task.interrupt(ctx);
                         self.clear_all(ctx);
                         return;
                     }
                 }
-                self.last_status = op.update(ctx);
+                self.last_status = match task.timed_out(ctx) {
+                    true => TaskStatus::Failure,
+                    false => op.update(ctx),
+                };
+                if self.last_status != TaskStatus::Continue {
+                    task.stop(ctx, self.last_status);
+                }
                 match self.last_status {
                     TaskStatus::Success => {
-                        // I don't actually reckon I need this tnbh, only for planning
-                        // for effect in task.effects.iter() {
-                        //     effect.apply(ctx);
-                        // }
+                        task.apply_effects(ctx);
                         self.current_task = None;
+                        ctx.state_mut().params.clear();
                         if self.plan.len() == 0 {
                             ctx.state_mut().last_record.clear();
                             ctx.state_mut().dirty = false;
@@ -145,21 +380,114 @@ impl<C> Planner<C>
                 }
             },
             None => {
-                // shouldn't really get here - if so, you may have set your behaviour up wrong
-                println!("Root task found with no operator! That is silly.");
+                // build() turns down primitives without operators, so this is a bug
+                warn!("Primitive task '{}' has no operator, failing it", task.name);
                 self.current_task = None;
                 self.last_status = TaskStatus::Failure;
             }
         }
     }
 
+    /// Drops whatever the planner is doing for a plan found some other way, e.g. by a
+    /// GoapPlanner. Its tasks have to be from the behaviour the planner is ticked with.
+    pub fn set_plan(&mut self, ctx: &mut C, behaviour: &Behaviour<C>, plan: Plan) {
+        if let Some(task_index) = self.current_task {
+            let filling = self.filling(behaviour, self.current_slot);
+            filling.as_deref().unwrap_or(behaviour).get_task(task_index).interrupt(ctx);
+        }
+        self.clear_all(ctx);
+        self.plan = plan;
+    }
+
     pub fn has_plan(&self) -> bool {
         self.plan.len() > 0
     }
 
+    /// The primitive tasks still to do after the current one, in order
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// The names of the tasks in plan(), looked up in the behaviour the planner is
+    /// ticked with, or the one in the slot a task came from
+    pub fn plan_names(&self, behaviour: &Behaviour<C>) -> Vec<String> {
+        self.plan.iter().filter_map(|step| self.task_name(behaviour, step.slot, step.task)).collect()
+    }
+
+    pub fn current_task(&self) -> Option<usize> {
+        self.current_task
+    }
+
+    /// The slot task whose behaviour the current task is from, None if it's the
+    /// planner's own
+    pub fn current_slot(&self) -> Option<usize> {
+        self.current_slot
+    }
+
+    pub fn current_task_name(&self, behaviour: &Behaviour<C>) -> Option<String> {
+        self.task_name(behaviour, self.current_slot, self.current_task?)
+    }
+
+    /// How the current task's last update went, or the last one's if it's finished
+    pub fn last_status(&self) -> TaskStatus {
+        self.last_status
+    }
+
+    /// How the last attempt at planning went, None if there hasn't been one
+    pub fn last_decomposition(&self) -> Option<DecompositionStatus> {
+        self.last_decomposition
+    }
+
+    fn task_name(&self, behaviour: &Behaviour<C>, slot: Option<usize>, task: usize) -> Option<String> {
+        match slot {
+            None => behaviour.task_name(task).map(|name| name.to_owned()),
+            Some(_) => self.filling(behaviour, slot)?.task_name(task).map(|name| name.to_owned()),
+        }
+    }
+
+    /// Seeds the rng random selectors and utility selector tie breaks use, so the same
+    /// seed and the same ticks make the same choices. Seeded from entropy otherwise.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// Record every task, condition and effect visited when finding plans. Costs an
+    /// allocation or two per condition, so leave it off unless you're debugging.
+    pub fn set_tracing(&mut self, tracing: bool) {
+        match (tracing, self.trace.is_some()) {
+            (true, false) => self.trace = Some(DecompositionTrace::default()),
+            (false, _) => self.trace = None,
+            _ => {},
+        }
+    }
+
+    /// The last decomposition, if tracing is on
+    pub fn trace(&self) -> Option<&DecompositionTrace> {
+        self.trace.as_ref()
+    }
+
+    /// Interrupts the current task, forgets the plan and everything the context
+    /// remembers about it, and asks for a replan. Done automatically when ticked with a
+    /// different behaviour than last time, e.g. after its asset was reloaded.
+    pub fn reset(&mut self, ctx: &mut C) {
+        if let (Some(_), Some(op)) = (self.current_task, self.running.take()) {
+            op.on_interrupt(ctx);
+        }
+        self.clear_all(ctx);
+        ctx.state_mut().record.clear();
+        // cooldowns are kept by task index too
+        ctx.state_mut().cooldowns.clear();
+        self.cooldown_wake = None;
+        ctx.state_mut().dirty = true;
+    }
+
     fn clear_all(&mut self, ctx: &mut C) {
         self.current_task = None;
+        self.current_slot = None;
+        self.running = None;
         self.plan.clear();
+        self.watched.clear();
+        ctx.state_mut().params.clear();
         ctx.state_mut().last_record.clear();
         ctx.state_mut().paused = false;
         ctx.state_mut().partial_queue.clear();
@@ -179,28 +507,24 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn tick_empty_behaviour_expectedbehav() {
-        let mut ctx = BeingContext::default();
-        let mut builder = BehaviourBuilder::new("test");
-        let b = builder.build();
-        let mut p = Planner::default();
-        p.tick(&b, &mut ctx);
+        let builder = BehaviourBuilder::<BeingContext>::new("test");
+        assert_eq!(builder.build().err(), Some(BehaviourError::Empty));
     }
 
     #[test]
     fn tick_primitive_no_operator_expectedbehav() {
         let mut ctx = BeingContext::default();
-        let mut builder = BehaviourBuilder::new("test");
-        builder
-            .sequence("super")
-                .primitive("primitive")
-                .end()
-            .end();
-        let b = builder.build();
+        let mut tasks = vec![
+            Task::new("super", 0, None, TaskType::Sequence),
+            Task::new("primitive", 1, Some(0), TaskType::Primitive),
+        ];
+        tasks[0].add_child(1);
+        // the builder won't make one of these, but a hand made behaviour can
+        let b = Behaviour::new("test", tasks);
         let mut p = Planner::default();
 
-        p.tick(&b, &mut ctx);
+        p.tick(&b, &mut ctx, 0.0);
 
         assert_eq!(p.current_task, None);
         assert_eq!(p.last_status, TaskStatus::Failure);
@@ -216,12 +540,46 @@ mod tests {
                     .do_action("test", |ctx: &mut BeingContext| {TaskStatus::Success})
                 .end()
             .end();
-        let b = builder.build();
+        let b = builder.build().unwrap();
         let mut p = Planner::default();
 
-        p.tick(&b, &mut ctx);
+        p.tick(&b, &mut ctx, 0.0);
 
         assert_eq!(p.last_status, TaskStatus::Success);
     }
 
+    #[test]
+    fn swapping_behaviour_resets_plan() {
+        let mut builder = BehaviourBuilder::new("old");
+        builder
+            .sequence("super")
+                .primitive("only")
+                    .do_action("test", |ctx: &mut BeingContext| {TaskStatus::Continue})
+                .end()
+            .end();
+        let old = builder.build().unwrap();
+        let mut builder = BehaviourBuilder::new("new");
+        builder
+            .sequence("super")
+                .primitive("first")
+                    .do_action("test", |ctx: &mut BeingContext| {TaskStatus::Success})
+                .end()
+                .primitive("second")
+                    .do_action("test", |ctx: &mut BeingContext| {TaskStatus::Continue})
+                .end()
+            .end();
+        let new = builder.build().unwrap();
+        let mut ctx = BeingContext::default();
+        let mut p = Planner::default();
+
+        p.tick(&old, &mut ctx, 0.0);
+        assert_eq!(p.current_task, Some(1));
+        assert!(!p.has_plan());
+
+        // the old plan would have carried on from index 1 without ever planning "second"
+        p.tick(&new, &mut ctx, 0.0);
+        assert_eq!(p.plan.iter().map(|step| step.task).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(p.behaviour_id, Some(new.id));
+    }
+
 }
\ No newline at end of file
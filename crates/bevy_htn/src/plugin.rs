@@ -1,47 +1,488 @@
-use bevy::prelude::*;
+use bevy_app::{App, CoreStage, Plugin};
+use bevy_ecs::{prelude::*, schedule::ShouldRun};
 use crate::prelude::*;
+use bevy_tasks::{ComputeTaskPool, TaskPool};
+use bevy_time::Time;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::Arc;
 
-pub struct AiPlugin;
+/// Gives every entity with a `C` context a planner, and ticks them all each frame
+/// against the behaviours in `BehaviourRegistry<C>`, planning in parallel on the
+/// ComputeTaskPool within the budget in `HtnSettings<C>`. Entities given a
+/// `GoapPlanner<C>` get no planner, and are ticked with theirs instead - outside the
+/// budget. One of these per context type, e.g.
+/// `app.add_plugin(HtnPlugin::<EnemyContext>::default())`.
+pub struct HtnPlugin<C, S = CoreStage>
+where
+    C: Context + Component,
+    S: StageLabel + Clone,
+{
+    stage: S,
+    pd: PhantomData<C>,
+}
+
+impl<C: Context + Component> Default for HtnPlugin<C> {
+    fn default() -> Self {
+        HtnPlugin::in_stage(CoreStage::Update)
+    }
+}
+
+impl<C, S> HtnPlugin<C, S>
+where
+    C: Context + Component,
+    S: StageLabel + Clone,
+{
+    /// Tick the planners in a stage other than CoreStage::Update
+    pub fn in_stage(stage: S) -> Self {
+        HtnPlugin {
+            stage: stage,
+            pd: PhantomData::default(),
+        }
+    }
+}
 
-impl Plugin for AiPlugin {
+impl<C, S> Plugin for HtnPlugin<C, S>
+where
+    C: Context + Component,
+    S: StageLabel + Clone,
+{
     fn build(&self, app: &mut App) {
+        // does nothing if the app's already made one
+        ComputeTaskPool::init(TaskPool::default);
         app
-        .add_startup_system(startup);
+        // bevy's TimePlugin keeps it up to date, without it the clock stands still
+        .init_resource::<Time>()
+        .init_resource::<BehaviourRegistry<C>>()
+        .init_resource::<HtnSettings<C>>()
+        .init_resource::<HtnClock<C>>()
+        .insert_resource(HtnStage::<C>::new(self.stage.clone()))
+        .add_system_to_stage(
+            self.stage.clone(),
+            insert_planners::<C>.exclusive_system().at_start().label(HtnSystem::InsertPlanners),
+        )
+        .add_system_to_stage(self.stage.clone(), measure_clock::<C>.label(HtnSystem::Clock).before(HtnSystem::Tick))
+        .add_system_set_to_stage(
+            self.stage.clone(),
+            SystemSet::new()
+                .with_run_criteria(htn_enabled::<C>)
+                .with_system(tick_planners::<C>.label(HtnSystem::Tick).after(HtnSystem::InsertPlanners))
+                .with_system(tick_goap_planners::<C>.label(HtnSystem::Tick).after(HtnSystem::Clock)),
+        );
     }
 }
 
-fn startup(
-    mut commands: &mut Commands,
-) {
-    let mut builder: BehaviourBuilder<BeingContext> = BehaviourBuilder::new("CreatureBehaviour");
-    use Variant::*;
-    builder
-        .sequence("BeTestBehaviour")
-            .sequence("FindEnemy")
-                .condition("No enemies in range", |ctx: &BeingContext| {
-                    !ctx.state().test_value("test", &Bool(true)).unwrap_or(false)
+#[derive(SystemLabel, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum HtnSystem {
+    InsertPlanners,
+    /// Sets HtnClock::delta from Time, unless it's manual
+    Clock,
+    /// Sensors, see AddSensor
+    Sense,
+    Tick,
+}
+
+// the stage the HtnPlugin<C> ticks in, so what's added for it later (e.g. sensors)
+// can go in it too and be ordered against its systems
+pub(crate) struct HtnStage<C> {
+    add_system_set: Arc<dyn Fn(&mut App, SystemSet) + Send + Sync>,
+    pd: PhantomData<C>,
+}
+
+impl<C: Context> HtnStage<C> {
+    fn new<S: StageLabel + Clone>(stage: S) -> Self {
+        HtnStage {
+            add_system_set: Arc::new(move |app: &mut App, set: SystemSet| {
+                app.add_system_set_to_stage(stage.clone(), set);
+            }),
+            pd: PhantomData::default(),
+        }
+    }
+
+    pub(crate) fn add_system_set(app: &mut App, set: SystemSet) {
+        let add = app.world.get_resource::<HtnStage<C>>()
+            .expect("the HtnPlugin has to be added first")
+            .add_system_set
+            .clone();
+        add(app, set);
+    }
+}
+
+/// Behaviours that agents with a `C` context can use, by name
+pub struct BehaviourRegistry<C: Context> {
+    behaviours: HashMap<String, Behaviour<C>>,
+    default: Option<String>,
+}
+
+impl<C: Context> Default for BehaviourRegistry<C> {
+    fn default() -> Self {
+        BehaviourRegistry {
+            behaviours: HashMap::default(),
+            default: None,
+        }
+    }
+}
+
+impl<C: Context> BehaviourRegistry<C> {
+    /// Registers a behaviour under its name. The first one registered becomes the
+    /// default for agents without a BehaviourName.
+    pub fn insert(&mut self, behaviour: Behaviour<C>) {
+        if self.default.is_none() {
+            self.default = Some(behaviour.name.clone());
+        }
+        self.behaviours.insert(behaviour.name.clone(), behaviour);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Behaviour<C>> {
+        self.behaviours.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Behaviour<C>> {
+        self.behaviours.get(name)
+    }
+
+    pub fn set_default(&mut self, name: &str) {
+        self.default = Some(name.to_owned());
+    }
+
+    pub fn get_default(&self) -> Option<&Behaviour<C>> {
+        self.behaviours.get(self.default.as_ref()?)
+    }
+}
+
+/// Which registered behaviour an agent uses. Agents without one use the default.
+#[derive(Component, Clone, PartialEq, Eq, Debug)]
+pub struct BehaviourName(pub String);
+
+/// Put on agents whose behaviour comes from a `Handle<Behaviour<C>>` by the
+/// HtnAssetPlugin, so they aren't also ticked against the registry.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct FromBehaviourAsset;
+
+/// Runtime switches for the HTN systems of one context type
+pub struct HtnSettings<C> {
+    /// Planners are only ticked while this is true
+    pub enabled: bool,
+    pub budget: PlanBudget,
+    /// How many agents each parallel task ticks
+    pub batch_size: usize,
+    pd: PhantomData<C>,
+}
+
+impl<C> Default for HtnSettings<C> {
+    fn default() -> Self {
+        HtnSettings {
+            enabled: true,
+            budget: PlanBudget::default(),
+            batch_size: 16,
+            pd: PhantomData::default(),
+        }
+    }
+}
+
+/// Limits on how much planning is done per frame, unlimited by default. Planners
+/// that want to plan past them carry on with what they're doing, and are first in
+/// line next frame - the longest waiting first.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct PlanBudget {
+    /// How many planners can plan per frame
+    pub max_replans: Option<usize>,
+    /// How many tasks can be visited decomposing per frame. Each planner is expected to
+    /// take as many as it did last time, so one that takes more can go over. The first
+    /// in line always plans, even if it's expected to go over on its own.
+    pub max_steps: Option<usize>,
+}
+
+// one frame of a PlanBudget, shared by the planners ticking in parallel
+pub(crate) struct FrameBudget {
+    admitted: HashSet<Entity>,
+}
+
+impl FrameBudget {
+    // waiting has the frame each planner that wants to plan started waiting, and the
+    // steps it's expected to take. They're all handed out here, before any planner
+    // runs, as counting them up while planning in parallel would race past the limit.
+    pub(crate) fn new(budget: &PlanBudget, mut waiting: Vec<(u64, Entity, usize)>) -> Self {
+        waiting.sort_unstable();
+        if let Some(max_replans) = budget.max_replans {
+            waiting.truncate(max_replans);
+        }
+        if let Some(max_steps) = budget.max_steps {
+            let mut reserved = 0;
+            let fits = waiting.iter()
+                .position(|(_, _, steps)| {
+                    reserved += steps;
+                    reserved > max_steps
                 })
-                .selector("MoveRandomly")
-                    .effect("Test!", |ctx: &mut BeingContext| ctx.state_mut().set("test", Bool(true)))
-                    .do_action("TestOp", |ctx: &mut BeingContext| {
-                        println!("I have many regrets; but the ass was fat");
+                .unwrap_or(waiting.len());
+            waiting.truncate(fits.max(1));
+        }
+        FrameBudget {
+            admitted: waiting.into_iter().map(|(_, entity, _)| entity).collect(),
+        }
+    }
+
+    fn may_plan(&self, entity: Entity) -> bool {
+        self.admitted.contains(&entity)
+    }
+}
+
+// the first half of a tick, returning the frame the planner started waiting to plan
+// and the steps it's expected to take - a task per task in the behaviour if it's yet
+// to plan
+pub(crate) fn prepare_within_budget<C: Context>(
+    planner: &mut Planner<C>,
+    behaviour: &Behaviour<C>,
+    ctx: &mut C,
+    delta: f32,
+    frame: u64,
+) -> Option<(u64, usize)> {
+    match planner.prepare(behaviour, ctx, delta) {
+        true => Some((
+            *planner.waiting_since.get_or_insert(frame),
+            planner.last_steps.unwrap_or(behaviour.tasks.len()),
+        )),
+        false => None,
+    }
+}
+
+// the second half, planning if the budget allows
+pub(crate) fn run_within_budget<C: Context>(
+    planner: &mut Planner<C>,
+    behaviour: &Behaviour<C>,
+    ctx: &mut C,
+    entity: Entity,
+    budget: &FrameBudget,
+) {
+    if let Some(steps) = planner.run(behaviour, ctx, budget.may_plan(entity)) {
+        planner.last_steps = Some(steps);
+        planner.waiting_since = None;
+    }
+}
+
+/// The delta planners for `C` are ticked with. Taken from bevy's Time every frame, so
+/// pausing or slowing the game does the same to the agents. Unless manual is set - then
+/// it's up to you to set delta before HtnSystem::Clock, which sensors and planners run after.
+pub struct HtnClock<C> {
+    pub delta: f32,
+    pub manual: bool,
+    pd: PhantomData<C>,
+}
+
+impl<C> Default for HtnClock<C> {
+    fn default() -> Self {
+        HtnClock {
+            delta: 0.0,
+            manual: false,
+            pd: PhantomData::default(),
+        }
+    }
+}
+
+/// Run criteria for systems that should only run while planning for `C` is enabled
+pub fn htn_enabled<C: Context>(settings: Res<HtnSettings<C>>) -> ShouldRun {
+    if settings.enabled {
+        ShouldRun::Yes
+    } else {
+        ShouldRun::No
+    }
+}
+
+// Exclusive and at the start of the stage, rather than through Commands, so agents
+// spawned before the stage are ticked the frame they appear
+fn insert_planners<C: Context + Component>(world: &mut World) {
+    let new: Vec<Entity> = world
+        .query_filtered::<Entity, (With<C>, Without<Planner<C>>, Without<GoapPlanner<C>>)>()
+        .iter(world)
+        .collect();
+    for entity in new {
+        world.entity_mut(entity).insert(Planner::<C>::default());
+    }
+}
+
+fn measure_clock<C: Context + Component>(time: Res<Time>, mut clock: ResMut<HtnClock<C>>) {
+    if !clock.manual {
+        clock.delta = time.delta_seconds();
+    }
+}
+
+fn tick_planners<C: Context + Component>(
+    registry: Res<BehaviourRegistry<C>>,
+    clock: Res<HtnClock<C>>,
+    settings: Res<HtnSettings<C>>,
+    mut frame: Local<u64>,
+    mut q_agents: Query<
+        (Entity, &mut C, &mut Planner<C>, Option<&BehaviourName>),
+        (Without<FromBehaviourAsset>, Without<GoapPlanner<C>>),
+    >,
+) {
+    let behaviour_of = |name: Option<&BehaviourName>| match name {
+        Some(name) => registry.get(&name.0),
+        None => registry.get_default(),
+    };
+    *frame += 1;
+    let mut waiting = vec![];
+    for (entity, mut ctx, mut planner, name) in q_agents.iter_mut() {
+        if let Some(behaviour) = behaviour_of(name) {
+            if let Some((since, steps)) = prepare_within_budget(&mut planner, behaviour, &mut *ctx, clock.delta, *frame) {
+                waiting.push((since, entity, steps));
+            }
+        }
+    }
+    let budget = FrameBudget::new(&settings.budget, waiting);
+    q_agents.par_for_each_mut(settings.batch_size, |(entity, mut ctx, mut planner, name)| {
+        if let Some(behaviour) = behaviour_of(name) {
+            run_within_budget(&mut planner, behaviour, &mut *ctx, entity, &budget);
+        }
+    });
+}
+
+// GOAP agents plan toward their goals with the primitives of their registered behaviour
+fn tick_goap_planners<C: Context + Component>(
+    registry: Res<BehaviourRegistry<C>>,
+    clock: Res<HtnClock<C>>,
+    settings: Res<HtnSettings<C>>,
+    mut q_agents: Query<(&mut C, &mut GoapPlanner<C>, Option<&BehaviourName>)>,
+) {
+    q_agents.par_for_each_mut(settings.batch_size, |(mut ctx, mut planner, name)| {
+        let behaviour = match name {
+            Some(name) => registry.get(&name.0),
+            None => registry.get_default(),
+        };
+        if let Some(behaviour) = behaviour {
+            planner.tick(behaviour, &mut *ctx, clock.delta);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DEFAULT: Key<i32> = Key::new("default");
+    static OTHER: Key<i32> = Key::new("other");
+    static COUNT: Key<i32> = Key::new("count");
+
+    fn counting_behaviour(name: &str, key: &'static Key<i32>) -> Behaviour<BeingContext> {
+        let mut builder = BehaviourBuilder::new(name);
+        builder
+            .sequence("root")
+                .primitive("count")
+                    .do_action("count", move |ctx: &mut BeingContext| {
+                        let count = match ctx.get(key) {
+                            Some(Variant::Int32(n)) => *n,
+                            _ => 0,
+                        };
+                        ctx.set(key, Variant::Int32(count + 1));
                         TaskStatus::Success
                     })
                 .end()
-            .end()
-            .sequence("MoveToEnemy")
-                .do_action("TestOp2", |ctx: &mut BeingContext| {
-                    println!("doing test op 2...");
-                    TaskStatus::Continue
-                })
-            .end()
-        .end();
-    let behaviour = builder.build();
-    behaviour.print();
-    let mut planner = Planner::default();
-    let mut ctx = BeingContext::new();
-    planner.tick(&behaviour, &mut ctx);
-    for _ in 1..10 {
-        planner.tick(&behaviour, &mut ctx);
-    }
-}
\ No newline at end of file
+            .end();
+        builder.build().unwrap()
+    }
+
+    fn count(app: &App, entity: Entity, key: &Key<i32>) -> Option<i32> {
+        match app.world.get::<BeingContext>(entity)?.get(key) {
+            Some(Variant::Int32(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn planners_are_added_and_ticked() {
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(counting_behaviour("default", &DEFAULT));
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(counting_behaviour("other", &OTHER));
+        let default_agent = app.world.spawn().insert(BeingContext::new()).id();
+        let other_agent = app.world.spawn().insert(BeingContext::new()).insert(BehaviourName("other".to_owned())).id();
+
+        // ticked from the frame they're spawned
+        app.update();
+        assert!(app.world.get::<Planner<BeingContext>>(default_agent).is_some());
+        assert_eq!(count(&app, default_agent, &DEFAULT), Some(1));
+        app.update();
+        assert_eq!(count(&app, default_agent, &DEFAULT), Some(2));
+        assert_eq!(count(&app, other_agent, &OTHER), Some(2));
+        assert_eq!(count(&app, other_agent, &DEFAULT), None);
+
+        app.world.resource_mut::<HtnSettings<BeingContext>>().enabled = false;
+        app.update();
+        assert_eq!(count(&app, default_agent, &DEFAULT), Some(2));
+    }
+
+    #[test]
+    fn planning_stays_within_the_step_budget() {
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(counting_behaviour("default", &COUNT));
+        app.world.resource_mut::<HtnSettings<BeingContext>>().budget.max_steps = Some(5);
+        let agents: Vec<Entity> = (0..4).map(|_| app.world.spawn().insert(BeingContext::new()).id()).collect();
+        let total = |app: &App| agents.iter().filter_map(|agent| count(app, *agent, &COUNT)).sum::<i32>();
+
+        // every plan takes two steps, so a third would go over
+        app.update();
+        assert_eq!(total(&app), 2);
+        app.update();
+        assert_eq!(total(&app), 4);
+        for agent in agents.iter() {
+            assert_eq!(count(&app, *agent, &COUNT), Some(1));
+        }
+    }
+
+    #[test]
+    fn replans_over_budget_wait_their_turn() {
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(counting_behaviour("default", &COUNT));
+        app.world.resource_mut::<HtnSettings<BeingContext>>().budget.max_replans = Some(1);
+        let agents: Vec<Entity> = (0..3).map(|_| app.world.spawn().insert(BeingContext::new()).id()).collect();
+
+        // every task succeeds straight away, so every agent wants to plan every frame
+        for round in 1..=2 {
+            for _ in 0..3 {
+                app.update();
+            }
+            for agent in agents.iter() {
+                assert_eq!(count(&app, *agent, &COUNT), Some(round));
+            }
+        }
+    }
+
+    #[test]
+    fn goap_agents_are_ticked_with_their_own_planner() {
+        static WOOD: Key<bool> = Key::new("wood");
+
+        let mut builder = BehaviourBuilder::new("default");
+        builder
+            .selector("actions")
+                .primitive("GatherSticks")
+                    .effect("wood", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&WOOD, Variant::Bool(true)))
+                    .do_action("gather", |ctx: &mut BeingContext| {
+                        let count = match ctx.get(&COUNT) {
+                            Some(Variant::Int32(n)) => *n,
+                            _ => 0,
+                        };
+                        ctx.set(&COUNT, Variant::Int32(count + 1));
+                        TaskStatus::Success
+                    })
+                .end()
+            .end();
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(builder.build().unwrap());
+        let mut ctx = BeingContext::new();
+        let mut planner = GoapPlanner::default();
+        planner.set_goal(&mut ctx, Goal::new("wood", |ctx: &BeingContext| ctx.get(&WOOD).is_some()));
+        let agent = app.world.spawn().insert(ctx).insert(planner).id();
+
+        // gathered once for the goal, then it's met - with no Planner running the
+        // behaviour as well, which would gather every frame
+        for _ in 0..3 {
+            app.update();
+        }
+        assert!(app.world.get::<Planner<BeingContext>>(agent).is_none());
+        assert_eq!(count(&app, agent, &COUNT), Some(1));
+        assert_eq!(app.world.get::<GoapPlanner<BeingContext>>(agent).unwrap().searches(), 2);
+    }
+}
@@ -0,0 +1,204 @@
+use bevy_app::App;
+use bevy_ecs::prelude::*;
+use crate::plugin::HtnStage;
+use crate::prelude::*;
+use std::collections::HashSet;
+
+// Sensors copy what's in the ECS into agents' contexts, so a new perception is a
+// closure rather than another system. e.g.
+//
+// static POSITION: Key<Vec2> = Key::new("position");
+//
+// app.add_sensor(Sensor::new(|transform: &Transform, ctx: &mut EnemyContext| {
+//     ctx.set_value(&POSITION, transform.translation.truncate());
+// }).every(0.25));
+
+/// Writes to the context of every agent with a `T` component, whenever its `T` has
+/// changed (or it's just got its context). Only the keys it gives a different value
+/// count as changed, so the only planners to replan are the ones that depend on them.
+pub struct Sensor<C, T> {
+    write: Box<dyn Fn(&T, &mut C) + Sync + Send>,
+    every: f32,
+}
+
+impl<C: Context + Component, T: Component> Sensor<C, T> {
+    pub fn new(write: impl Fn(&T, &mut C) + Sync + Send + 'static) -> Self {
+        Sensor {
+            write: Box::new(write),
+            every: 0.0,
+        }
+    }
+
+    /// Senses at most once per this many seconds of `HtnClock<C>` time, catching up
+    /// on everything that changed in between. Every frame by default.
+    pub fn every(mut self, seconds: f32) -> Self {
+        self.every = seconds;
+        self
+    }
+}
+
+pub trait AddSensor {
+    /// Runs the sensor in the HtnPlugin's stage, after HtnClock<C> is set and before the
+    /// planners are ticked, and only while planning for `C` is enabled. Needs the
+    /// `HtnPlugin<C>` to be added first.
+    fn add_sensor<C: Context + Component, T: Component>(&mut self, sensor: Sensor<C, T>) -> &mut Self;
+}
+
+impl AddSensor for App {
+    fn add_sensor<C: Context + Component, T: Component>(&mut self, sensor: Sensor<C, T>) -> &mut Self {
+        let Sensor { write, every } = sensor;
+        // changes are remembered until it's due, so none are lost in between
+        let sense = move |
+            clock: Res<HtnClock<C>>,
+            mut waited: Local<Option<f32>>,
+            mut pending: Local<HashSet<Entity>>,
+            mut q_agents: Query<(Entity, &T, &mut C, ChangeTrackers<T>)>,
+        | {
+            // starts due, so the first frame senses
+            let waited = waited.get_or_insert(every);
+            *waited += clock.delta;
+            let due = *waited >= every;
+            if due {
+                *waited = 0.0;
+            }
+            for (entity, component, mut ctx, sensed) in q_agents.iter_mut() {
+                let changed = sensed.is_changed() || ctx.is_added();
+                if due && (changed || pending.contains(&entity)) {
+                    write(component, &mut *ctx);
+                } else if changed {
+                    pending.insert(entity);
+                }
+            }
+            if due {
+                // the rest were despawned or lost their components
+                pending.clear();
+            }
+        };
+        HtnStage::<C>::add_system_set(
+            self,
+            SystemSet::new()
+                .with_run_criteria(htn_enabled::<C>)
+                .with_system(sense.label(HtnSystem::Sense).after(HtnSystem::Clock).before(HtnSystem::Tick)),
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_app::CoreStage;
+
+    static HUNGER: Key<i32> = Key::new("hunger");
+    static WRITES: Key<i32> = Key::new("writes");
+
+    #[derive(Component)]
+    struct Hunger(i32);
+
+    fn sensed(app: &App, entity: Entity, key: &Key<i32>) -> Option<i32> {
+        match app.world.get::<BeingContext>(entity)?.get(key) {
+            Some(Variant::Int32(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn hunger_sensor() -> Sensor<BeingContext, Hunger> {
+        Sensor::new(|hunger: &Hunger, ctx: &mut BeingContext| {
+            let writes = match ctx.get(&WRITES) {
+                Some(Variant::Int32(n)) => *n,
+                _ => 0,
+            };
+            ctx.set(&WRITES, Variant::Int32(writes + 1));
+            ctx.set(&HUNGER, Variant::Int32(hunger.0));
+        })
+    }
+
+    #[test]
+    fn sensors_write_only_when_their_component_changes() {
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.add_sensor(hunger_sensor());
+        let agent = app.world.spawn().insert(BeingContext::new()).insert(Hunger(3)).id();
+
+        app.update();
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(3));
+        assert_eq!(sensed(&app, agent, &WRITES), Some(1));
+
+        app.world.get_mut::<Hunger>(agent).unwrap().0 = 4;
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(4));
+        assert_eq!(sensed(&app, agent, &WRITES), Some(2));
+    }
+
+    #[test]
+    fn sensors_catch_up_at_their_frequency() {
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.add_sensor(hunger_sensor().every(0.25));
+        {
+            let mut clock = app.world.resource_mut::<HtnClock<BeingContext>>();
+            clock.manual = true;
+            clock.delta = 0.1;
+        }
+        let agent = app.world.spawn().insert(BeingContext::new()).insert(Hunger(3)).id();
+
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(3));
+
+        app.world.get_mut::<Hunger>(agent).unwrap().0 = 4;
+        app.update();
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(3));
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(4));
+    }
+
+    #[test]
+    fn sensors_go_by_this_frames_clock() {
+        struct NextDelta(f32);
+
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext>::default());
+        app.add_sensor(hunger_sensor().every(0.25));
+        app.world.resource_mut::<HtnClock<BeingContext>>().manual = true;
+        app.insert_resource(NextDelta(0.0));
+        app.add_system(
+            (|next: Res<NextDelta>, mut clock: ResMut<HtnClock<BeingContext>>| clock.delta = next.0)
+                .before(HtnSystem::Clock),
+        );
+        let agent = app.world.spawn().insert(BeingContext::new()).insert(Hunger(3)).id();
+        app.update();
+
+        app.world.get_mut::<Hunger>(agent).unwrap().0 = 4;
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(3));
+        app.world.resource_mut::<NextDelta>().0 = 0.3;
+        app.update();
+        assert_eq!(sensed(&app, agent, &HUNGER), Some(4));
+    }
+
+    #[test]
+    fn sensors_run_before_planners_in_another_stage() {
+        static SEEN: Key<i32> = Key::new("seen");
+
+        let mut builder = BehaviourBuilder::new("test");
+        builder
+            .primitive("look")
+                .do_action("look", |ctx: &mut BeingContext| {
+                    if let Some(Variant::Int32(hunger)) = ctx.get(&HUNGER).cloned() {
+                        ctx.set(&SEEN, Variant::Int32(hunger));
+                    }
+                    TaskStatus::Success
+                })
+            .end();
+        let mut app = App::new();
+        app.add_plugin(HtnPlugin::<BeingContext, _>::in_stage(CoreStage::PreUpdate));
+        app.add_sensor(hunger_sensor());
+        app.world.resource_mut::<BehaviourRegistry<BeingContext>>().insert(builder.build().unwrap());
+        let agent = app.world.spawn().insert(BeingContext::new()).insert(Hunger(3)).id();
+
+        app.update();
+        assert_eq!(sensed(&app, agent, &SEEN), Some(3));
+    }
+}
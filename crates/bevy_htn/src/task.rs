@@ -1,12 +1,25 @@
 use crate::prelude::*;
+use rand::Rng;
+use std::cmp::Ordering;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum TaskType {
     Sequence,
     Selector,
     Primitive,
     Pause,
+    /// Tries its sub-tasks best score first
+    UtilitySelector,
+    /// Decomposes as a named compound task from elsewhere in the behaviour
+    Reference,
+    /// Decomposes as whatever behaviour the planner has filled it with, if any
+    Slot,
+    /// A sequence of its sub-tasks, done over again a number of times
+    Repeat,
+    /// Tries its sub-tasks in a random order, weighted
+    RandomSelector,
 }
 
 pub struct Task<C> 
@@ -15,13 +28,24 @@ where
 {
     pub(super) name: String,
     pub(super) index: usize,
-    pub(super) conditions: Vec<Box<dyn Condition<C>>>,
-    pub(super) exec_conditions: Vec<Box<dyn Condition<C>>>, // conditions checked every execute
-    pub(super) operator: Option<Box<dyn Operator<C>>>,
-    pub(super) effects: Vec<Box<dyn Effect<C>>>,
+    pub(super) conditions: Vec<(String, Box<dyn Condition<C>>)>,
+    pub(super) exec_conditions: Vec<(String, Box<dyn Condition<C>>)>, // conditions checked every execute
+    pub(super) dependencies: Vec<KeyId>, // context keys the conditions read
+    pub(super) bindings: Vec<(String, Binding)>, // parameters for this task and its sub-tasks
+    pub(super) operator: Option<Arc<dyn Operator<C>>>, // shared with the planner running it
+    pub(super) operator_name: String,
+    pub(super) effects: Vec<(String, EffectType, Box<dyn Effect<C>>)>,
     pub(super) parent: Option<usize>,
     pub(super) sub_tasks: Vec<usize>,
     pub(super) task_type: TaskType,
+    pub(super) target: Option<usize>, // what a reference refers to, set when built
+    pub(super) score: Option<(String, Box<dyn Score<C>>)>, // for a utility selector parent
+    pub(super) random_ties: bool, // utility selectors only
+    pub(super) weight: f32, // for a random selector parent
+    pub(super) repeat: usize, // times a sequence's sub-tasks are done, 1 unless it's a Repeat
+    pub(super) cooldown: Option<f32>, // seconds after being chosen it can't be again
+    pub(super) timeout: Option<f32>, // seconds a primitive can run before it fails
+    pub(super) cost: f32, // of a primitive, to a GoapPlanner
     pd: PhantomData<C>,
 }
 
@@ -32,18 +56,41 @@ impl<C: Context> Task<C> {
             index: index,
             conditions: vec![],
             exec_conditions: vec![],
+            dependencies: vec![],
+            bindings: vec![],
             operator: None,
+            operator_name: String::new(),
             effects: vec![],
             parent: parent,
             sub_tasks: vec![],
             task_type: task_type,
+            target: None,
+            score: None,
+            random_ties: false,
+            weight: 1.0,
+            repeat: 1,
+            cooldown: None,
+            timeout: None,
+            cost: 1.0,
             pd: PhantomData::default(),
         }
     }
 
-    pub fn stop(&self, ctx: &mut C) {
-        if self.operator.is_some() {
-            self.operator.as_ref().unwrap().stop(ctx);
+    pub(crate) fn start(&self, ctx: &mut C) {
+        if let Some(op) = self.operator.as_ref() {
+            op.on_start(ctx);
+        }
+    }
+
+    pub(crate) fn stop(&self, ctx: &mut C, status: TaskStatus) {
+        if let Some(op) = self.operator.as_ref() {
+            op.on_stop(ctx, status);
+        }
+    }
+
+    pub(crate) fn interrupt(&self, ctx: &mut C) {
+        if let Some(op) = self.operator.as_ref() {
+            op.on_interrupt(ctx);
         }
     }
 
@@ -57,32 +104,106 @@ impl<C: Context> Task<C> {
     }
 
     pub(crate) fn is_valid(&self, ctx: &C) -> bool {
-        let mut valid = true;
-        for cond in self.conditions.iter() {
-            valid = valid && cond.is_valid(ctx);
-        }
-        valid
+        self.conditions.iter().all(|(_, cond)| cond.is_valid(ctx))
     }
 
+    pub(crate) fn timed_out(&self, ctx: &C) -> bool {
+        self.timeout.map_or(false, |timeout| ctx.state().task_time() >= timeout)
+    }
+
+    pub(crate) fn depends_on(&self, key: KeyId) -> bool {
+        self.dependencies.contains(&key)
+    }
+
+    /// While planning every effect is applied, and permanent ones are marked so they
+    /// outlive the planning transaction. Once executing only PlanAndExecute effects are.
     pub fn apply_effects(&self, ctx: &mut C) {
-        for effect in self.effects.iter() {
-            effect.apply(ctx);
+        let planning = matches!(ctx.state().exec_state, ExecutionState::Planning);
+        for (_, effect_type, effect) in self.effects.iter() {
+            match (planning, effect_type) {
+                (true, EffectType::Permanent) => {
+                    ctx.state_mut().uncacheable = true;
+                    ctx.state_mut().permanent_writes = true;
+                    effect.apply(ctx);
+                    ctx.state_mut().permanent_writes = false;
+                },
+                (true, _) | (false, EffectType::PlanAndExecute) => effect.apply(ctx),
+                _ => {},
+            }
         }
     }
 
-    pub (crate) fn add_operator(&mut self, operator: Box<dyn Operator<C>>) {
+    pub (crate) fn add_operator(&mut self, name: &str, operator: Arc<dyn Operator<C>>) {
         assert!(self.task_type == TaskType::Primitive);
         self.operator = Some(operator);
+        self.operator_name = name.to_owned();
     }
 
-    pub (crate) fn decompose(&self, ctx: &mut C, behaviour: &Behaviour<C>, plan: &mut Plan) 
+    pub (crate) fn decompose(&self, ctx: &mut C, behaviour: &Behaviour<C>, plan: &mut Plan, scope: Scope<C>) 
         -> DecompositionStatus
     {
-        let mut decomposition = TaskDecomposition::new(self.index, ctx, behaviour);
-        decomposition.decompose(&self, plan)
+        let mut decomposition = TaskDecomposition::new(self.index, ctx, behaviour, scope);
+        match behaviour.mode {
+            DecompositionMode::FirstMatch => decomposition.decompose(&self, plan),
+            DecompositionMode::Backtracking { .. } => {
+                let plan_len = plan.len();
+                let mut agenda = vec![Agenda::Task(self.index)];
+                let status = decomposition.decompose_agenda(&mut agenda, plan);
+                if status == DecompositionStatus::Failed || status == DecompositionStatus::Rejected {
+                    plan.truncate(plan_len);
+                }
+                status
+            },
+        }
+    }
+}
+
+
+// What a decomposition can see beyond the behaviour it's decomposing
+pub(crate) struct Scope<'s, C: Context> {
+    pub slots: &'s Slots<C>,
+    // the slot task (in the planner's behaviour) that the behaviour being decomposed
+    // fills, None for the planner's own behaviour
+    pub slot: Option<usize>,
+}
+
+// not derived, so C doesn't have to be Clone
+impl<'s, C: Context> Clone for Scope<'s, C> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
 
+impl<'s, C: Context> Copy for Scope<'s, C> {}
+
+// what's left for a backtracking decomposition to do, next one last
+#[derive(Clone, Copy)]
+enum Agenda {
+    Task(usize),
+    // the referenced subtree before it is done, so the reference is left
+    Return,
+}
+
+// a selector in a backtracking decomposition, with the sub-tasks it's yet to try and
+// what to put back before trying the next one
+struct Choice {
+    task: usize,
+    order: Vec<usize>,
+    next: usize,
+    agenda: Vec<Agenda>,
+    calls: Vec<(Option<usize>, usize)>,
+    record_len: usize,
+    cooldowns_len: usize,
+    diverged: bool,
+    beaten: bool,
+    plan_len: usize,
+}
+
+// what a step of a backtracking decomposition leaves to do
+enum Step {
+    Next,
+    Done(DecompositionStatus),
+}
 
 struct TaskDecomposition<'s, C> 
 where
@@ -90,37 +211,52 @@ where
 {
     ctx: &'s mut C,
     behaviour: &'s Behaviour<C>,
+    scope: Scope<'s, C>,
     calling_task: usize,
+    steps: usize,
 }
 
 impl<'s, C> TaskDecomposition<'s, C> 
 where
     C: Context
 {
-    pub fn new(calling: usize, ctx: &'s mut C, behaviour: &'s Behaviour<C>) -> Self {
+    pub fn new(calling: usize, ctx: &'s mut C, behaviour: &'s Behaviour<C>, scope: Scope<'s, C>) -> Self {
         TaskDecomposition::<C> {
             ctx: ctx,
             behaviour: behaviour,
+            scope: scope,
             calling_task: calling,
+            steps: 0,
         }
     }
 
     pub fn decompose(&mut self, task: &Task<C>, over_plan: &mut Plan) -> DecompositionStatus {
         use TaskType::*;
 
+        self.ctx.state_mut().steps += 1;
+        self.trace_visit(task);
         match task.get_type() {
-            Sequence => {
+            Sequence | Repeat => {
                 return self.decompose_sequence(task, over_plan);
             },
             Selector => {
                 return self.decompose_selector(task, over_plan);
             }
+            UtilitySelector | RandomSelector => {
+                return self.decompose_utility_selector(task, over_plan);
+            }
             Pause => {
                 return self.decompose_pause(over_plan);
             },
             Primitive => {
                 return self.decompose_primitive(task, over_plan);
             },
+            Reference => {
+                return self.decompose_reference(task, over_plan);
+            },
+            Slot => {
+                return self.decompose_slot(task, over_plan);
+            },
         }
     
     }
@@ -130,13 +266,14 @@ where
 
         let mut sub_plan = Plan::default();
         self.ctx.state_mut().begin_transaction();
-        for sub_task_inx in task.sub_tasks.iter() {
+        let times = task.sub_tasks.len() * task.repeat;
+        for sub_task_inx in task.sub_tasks.iter().cycle().take(times) {
             let sub_task = self.behaviour.get_task(*sub_task_inx);
-            if !task.is_valid(self.ctx) {
-                sub_plan.clear();
+            if !self.is_valid(task) {
+                self.ctx.state_mut().rollback_transaction();
                 return Failed;
             }
-            let status = sub_task.decompose(self.ctx, self.behaviour, &mut sub_plan);
+            let status = sub_task.decompose(self.ctx, self.behaviour, &mut sub_plan, self.scope);
             match status {
                 Rejected | Failed | Partial => {
                     self.ctx.state_mut().rollback_transaction();                    
@@ -147,10 +284,13 @@ where
         }
 
         match sub_plan.len() {
-            l if l == 0 => Failed,
+            l if l == 0 => {
+                self.ctx.state_mut().rollback_transaction();
+                Failed
+            },
             _ => {
                 self.ctx.state_mut().commit_transaction();
-                over_plan.extend(sub_plan.iter());
+                over_plan.extend(sub_plan.drain(..));
                 Succeeded
             }
         }
@@ -160,54 +300,538 @@ where
         use DecompositionStatus::*;
 
         let mut sub_plan = Plan::default();
-        // TODO check if current task we're about to decompose
-        // can possibly beat the running plan
-        for sub_task_inx in task.sub_tasks.iter() {
+        for (position, sub_task_inx) in task.sub_tasks.iter().enumerate() {
+            if !self.can_beat_last_record(position) {
+                self.trace_rejected(task);
+                return Rejected;
+            }
             let sub_task = self.behaviour.get_task(*sub_task_inx);
-            if !task.is_valid(self.ctx) {
-                over_plan.extend(sub_plan.iter());
+            if !self.is_valid(task) {
+                over_plan.extend(sub_plan.drain(..));
                 return Failed
             }
-            let status = sub_task.decompose(self.ctx, self.behaviour, &mut sub_plan);
+            let record_len = self.ctx.state().record.len();
+            let cooldowns_len = self.ctx.state().pending_cooldowns.len();
+            let beaten = self.ctx.state().beaten;
+            self.record_choice(position);
+            let status = sub_task.decompose(self.ctx, self.behaviour, &mut sub_plan, self.scope);
             match status {
                 Rejected | Succeeded | Partial => {
-                    over_plan.extend(sub_plan.iter());
+                    over_plan.extend(sub_plan.drain(..));
                     return status;
                 },
-                _ => continue
+                // forget anything this branch chose, it's not part of the plan
+                _ => {
+                    self.ctx.state_mut().record.truncate(record_len);
+                    self.ctx.state_mut().pending_cooldowns.truncate(cooldowns_len);
+                    self.ctx.state_mut().beaten = beaten;
+                },
             }
         }
 
         Failed
+    }
+
+    fn decompose_utility_selector(&mut self, task: &Task<C>, over_plan: &mut Plan) -> DecompositionStatus {
+        use DecompositionStatus::*;
+
+        if !self.is_valid(task) {
+            return Failed;
+        }
+        let mut sub_plan = Plan::default();
+        let order = match task.task_type {
+            TaskType::RandomSelector => self.shuffle(task),
+            _ => self.rank(task),
+        };
+        for position in order {
+            let sub_task = self.behaviour.get_task(task.sub_tasks[position]);
+            let record_len = self.ctx.state().record.len();
+            let cooldowns_len = self.ctx.state().pending_cooldowns.len();
+            let diverged = self.ctx.state().diverged;
+            let beaten = self.ctx.state().beaten;
+            self.record_utility_choice(position);
+            let status = sub_task.decompose(self.ctx, self.behaviour, &mut sub_plan, self.scope);
+            match status {
+                Rejected | Succeeded | Partial => {
+                    over_plan.extend(sub_plan.drain(..));
+                    return status;
+                },
+                _ => {
+                    self.ctx.state_mut().record.truncate(record_len);
+                    self.ctx.state_mut().pending_cooldowns.truncate(cooldowns_len);
+                    self.ctx.state_mut().diverged = diverged;
+                    self.ctx.state_mut().beaten = beaten;
+                },
+            }
+        }
+
+        Failed
+    }
+
+    // positions of the sub-tasks, best score first. Equal scores stay in the order
+    // they were added unless the selector breaks ties randomly.
+    fn rank(&mut self, task: &Task<C>) -> Vec<usize> {
+        let mut ranked: Vec<(f32, u32, usize)> = Vec::with_capacity(task.sub_tasks.len());
+        for (position, sub_task_inx) in task.sub_tasks.iter().enumerate() {
+            let sub_task = self.behaviour.get_task(*sub_task_inx);
+            // every score decides the plan, so they're all watched
+            if !sub_task.dependencies.is_empty() {
+                self.ctx.state_mut().visited.push((self.scope.slot, sub_task.index));
+            }
+            let score = match &sub_task.score {
+                Some((name, score)) => {
+                    let score = score.score(self.ctx);
+                    if self.ctx.state().trace.is_some() {
+                        let path = self.behaviour.path_of(sub_task.index);
+                        self.ctx.state_mut().trace(TraceEvent::Score { path: path, name: name.clone(), score: score });
+                    }
+                    score
+                },
+                None => 0.0,
+            };
+            if task.random_ties {
+                self.ctx.state_mut().uncacheable = true;
+            }
+            let tie_break = match self.ctx.state_mut().rng.as_mut() {
+                Some(rng) if task.random_ties => rng.gen(),
+                _ => 0,
+            };
+            ranked.push((score, tie_break, position));
+        }
+        ranked.sort_by(|a, b| {
+            b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal)
+                .then(a.1.cmp(&b.1))
+                .then(a.2.cmp(&b.2))
+        });
+        ranked.into_iter().map(|(_, _, position)| position).collect()
+    }
+
+    // positions of the sub-tasks in a random order, where each one's chance of coming
+    // before the rest goes by its weight. In order if the planner hasn't lent an rng.
+    fn shuffle(&mut self, task: &Task<C>) -> Vec<usize> {
+        let mut keyed: Vec<(f32, usize)> = Vec::with_capacity(task.sub_tasks.len());
+        self.ctx.state_mut().uncacheable = true;
+        for (position, sub_task_inx) in task.sub_tasks.iter().enumerate() {
+            let weight = self.behaviour.get_task(*sub_task_inx).weight;
+            // weighted sampling without replacement, biggest key first
+            let key = match self.ctx.state_mut().rng.as_mut() {
+                Some(rng) if weight > 0.0 => rng.gen::<f32>().powf(1.0 / weight),
+                Some(_) => -1.0,
+                None => 0.0,
+            };
+            keyed.push((key, position));
+        }
+        keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then(a.1.cmp(&b.1)));
+        keyed.into_iter().map(|(_, position)| position).collect()
+    }
+
+    // scores change all the time, and random choices every time, so a utility or random
+    // selector's choice can't be beaten or not by position - if it differs from the
+    // running plan's, nothing after it compares
+    fn record_utility_choice(&mut self, position: usize) {
+        let state = self.ctx.state_mut();
+        if state.last_record.get(state.record.len()) != Some(position) {
+            state.diverged = true;
+        }
+        state.record.add(position);
+    }
+
+    // a plain selector's choice. Picking an earlier sub-task than the running plan did
+    // means the new plan beats it, so nothing after this has to be compared.
+    fn record_choice(&mut self, position: usize) {
+        let state = self.ctx.state_mut();
+        if !state.diverged && state.last_record.get(state.record.len()).map_or(false, |last| position < last) {
+            state.beaten = true;
+        }
+        state.record.add(position);
+    }
+
+    // same as Task::is_valid, but tells the trace about every condition checked, and
+    // remembers the task so the planner can watch what its conditions depend on. Binds
+    // the task's parameters first so its conditions (and effects) can use them. A task
+    // with a cooldown is held back until it's over, and otherwise starts it again if
+    // the plan is accepted.
+    fn is_valid(&mut self, task: &Task<C>) -> bool {
+        if !task.dependencies.is_empty() {
+            self.ctx.state_mut().visited.push((self.scope.slot, task.index));
+        }
+        if let Some(cooldown) = task.cooldown {
+            self.ctx.state_mut().uncacheable = true;
+            let left = self.ctx.state().cooldown_left(self.scope.slot, task.index);
+            if self.ctx.state().trace.is_some() {
+                let path = self.behaviour.path_of(task.index);
+                let name = "cooled down".to_owned();
+                self.ctx.state_mut().trace(TraceEvent::Condition { path: path, name: name, valid: left == 0.0 });
+            }
+            if left > 0.0 {
+                let state = self.ctx.state_mut();
+                let ready = state.now + left;
+                state.cooldown_wake = Some(state.cooldown_wake.map_or(ready, |wake| wake.min(ready)));
+                return false;
+            }
+            self.ctx.state_mut().pending_cooldowns.push(((self.scope.slot, task.index), cooldown));
+        }
+        if !self.bind_params(task) {
+            return false;
+        }
+        for (name, condition) in task.conditions.iter() {
+            let valid = condition.is_valid(self.ctx);
+            if self.ctx.state().trace.is_some() {
+                let path = self.behaviour.path_of(task.index);
+                self.ctx.state_mut().trace(TraceEvent::Condition { path: path, name: name.clone(), valid: valid });
+            }
+            if !valid {
+                return false;
+            }
+        }
+        true
+    }
 
-        // probably don't need this...
-        // match sub_plan.len() {
-        //     l if l == 0 => Failed,
-        //     _ => {
-        //         over_plan.extend(sub_plan.iter());
-        //         Succeeded
-        //     }
-        // }
+    // parameters come from the task's own bindings and its parents', the nearest
+    // binding of a name winning. Above a referenced task they come from the reference
+    // and its parents rather than the referenced task's own. False if a binding's key
+    // isn't in the context.
+    fn bind_params(&mut self, task: &Task<C>) -> bool {
+        self.ctx.state_mut().params.clear();
+        let mut calls = self.ctx.state().calls.len();
+        let mut next = Some(task.index);
+        while let Some(index) = next {
+            let bound_task = self.behaviour.get_task(index);
+            for (name, binding) in bound_task.bindings.iter() {
+                if self.ctx.state().params.contains_key(name) {
+                    continue;
+                }
+                let value = match binding {
+                    Binding::Value(value) => value.clone(),
+                    Binding::Key(key) => match self.ctx.get(key) {
+                        Some(value) => value.clone(),
+                        None => {
+                            if self.ctx.state().trace.is_some() {
+                                let path = self.behaviour.path_of(task.index);
+                                let name = format!("{} is bound", name);
+                                self.ctx.state_mut().trace(TraceEvent::Condition { path: path, name: name, valid: false });
+                            }
+                            return false;
+                        },
+                    },
+                };
+                self.ctx.state_mut().params.insert(name.clone(), value);
+            }
+            next = bound_task.parent;
+            // back out of the reference that got here, if it's in this behaviour and not
+            // the one whose slot it fills
+            if let Some((slot, reference)) = calls.checked_sub(1).map(|last| self.ctx.state().calls[last]) {
+                if slot == self.scope.slot && self.behaviour.get_task(reference).target == Some(index) {
+                    calls -= 1;
+                    next = Some(reference);
+                }
+            }
+        }
+        true
+    }
+
+    fn plan_step(&self, task: &Task<C>) -> PlanStep {
+        PlanStep {
+            task: task.index,
+            slot: self.scope.slot,
+            params: self.ctx.state().params.clone(),
+        }
+    }
+
+    fn apply_effects(&mut self, task: &Task<C>) {
+        task.apply_effects(self.ctx);
+        if self.ctx.state().trace.is_some() {
+            let path = self.behaviour.path_of(task.index);
+            for (name, effect_type, _) in task.effects.iter() {
+                self.ctx.state_mut().trace(TraceEvent::Effect {
+                    path: path.clone(),
+                    name: name.clone(),
+                    effect_type: *effect_type,
+                });
+            }
+        }
+    }
+
+    fn trace_visit(&mut self, task: &Task<C>) {
+        if self.ctx.state().trace.is_some() {
+            let path = self.behaviour.path_of(task.index);
+            self.ctx.state_mut().trace(TraceEvent::Task { path: path, task_type: task.task_type });
+        }
+    }
+
+    fn trace_rejected(&mut self, task: &Task<C>) {
+        if self.ctx.state().trace.is_some() {
+            let path = self.behaviour.path_of(task.index);
+            self.ctx.state_mut().trace(TraceEvent::Rejected { path: path });
+        }
+    }
+
+    // a selector choosing a later sub-task than the running plan did at the same
+    // point can only ever produce a lower priority plan, so there's no point going on
+    fn can_beat_last_record(&self, position: usize) -> bool {
+        let state = self.ctx.state();
+        if state.diverged || state.beaten || state.last_record.is_empty() || state.record.len() >= state.last_record.len() {
+            return true;
+        }
+        match state.last_record.get(state.record.len()) {
+            Some(last_position) => position <= last_position,
+            None => true,
+        }
+    }
+
+    // Backtracking decomposition. The agenda is a stack of the tasks still left to
+    // decompose (next one on top), so when a selector picks a sub-task it gets to see
+    // whether everything after it in the enclosing sequences can still be planned, and
+    // can move on to its next alternative if not. The selectors still choosing are kept
+    // on a stack of their own, so it's a loop however many tasks it goes through.
+    fn decompose_agenda(&mut self, agenda: &mut Vec<Agenda>, over_plan: &mut Plan) -> DecompositionStatus {
+        use DecompositionStatus::*;
+
+        let calls = self.ctx.state().calls.clone();
+        let mut choices: Vec<Choice> = vec![];
+        let status = loop {
+            let mut step = self.agenda_step(agenda, &mut choices, over_plan);
+            if let Step::Done(Failed) = step {
+                step = self.backtrack(&mut choices, agenda, over_plan);
+            }
+            match step {
+                Step::Next => continue,
+                Step::Done(status @ (Succeeded | Partial)) => {
+                    for _ in choices.iter() {
+                        self.ctx.state_mut().commit_transaction();
+                    }
+                    break status;
+                },
+                Step::Done(status) => {
+                    while let Some(choice) = choices.pop() {
+                        self.undo_choice(&choice, over_plan);
+                    }
+                    break status;
+                },
+            }
+        };
+        self.ctx.state_mut().calls = calls;
+        status
+    }
+
+    // decomposes the task on top of the agenda
+    fn agenda_step(&mut self, agenda: &mut Vec<Agenda>, choices: &mut Vec<Choice>, over_plan: &mut Plan) -> Step {
+        use DecompositionStatus::*;
+
+        let task_index = match agenda.pop() {
+            Some(Agenda::Task(task_index)) => task_index,
+            Some(Agenda::Return) => {
+                self.ctx.state_mut().calls.pop().expect("returned from no reference");
+                return Step::Next;
+            },
+            None => return Step::Done(Succeeded),
+        };
+
+        self.steps += 1;
+        if let DecompositionMode::Backtracking { max_steps } = self.behaviour.mode {
+            if self.steps > max_steps {
+                return Step::Done(Failed);
+            }
+        }
+
+        let task = self.behaviour.get_task(task_index);
+        self.ctx.state_mut().steps += 1;
+        self.trace_visit(task);
+        match task.get_type() {
+            TaskType::Sequence | TaskType::Repeat => {
+                if !self.is_valid(task) {
+                    return Step::Done(Failed);
+                }
+                for _ in 0..task.repeat {
+                    agenda.extend(task.sub_tasks.iter().rev().map(|sub_task| Agenda::Task(*sub_task)));
+                }
+                Step::Next
+            },
+            TaskType::Selector | TaskType::UtilitySelector | TaskType::RandomSelector => {
+                if !self.is_valid(task) {
+                    return Step::Done(Failed);
+                }
+                let order = match task.task_type {
+                    TaskType::UtilitySelector => self.rank(task),
+                    TaskType::RandomSelector => self.shuffle(task),
+                    _ => (0..task.sub_tasks.len()).collect(),
+                };
+                let state = self.ctx.state();
+                choices.push(Choice {
+                    task: task_index,
+                    order: order,
+                    next: 0,
+                    agenda: agenda.clone(),
+                    calls: state.calls.clone(),
+                    record_len: state.record.len(),
+                    cooldowns_len: state.pending_cooldowns.len(),
+                    diverged: state.diverged,
+                    beaten: state.beaten,
+                    plan_len: over_plan.len(),
+                });
+                self.next_choice(choices, agenda)
+            },
+            TaskType::Primitive => {
+                if !self.is_valid(task) {
+                    return Step::Done(Failed);
+                }
+                self.apply_effects(task);
+                over_plan.push_back(self.plan_step(task));
+                Step::Next
+            },
+            TaskType::Reference => {
+                if !self.can_go_deeper(task) {
+                    return Step::Done(Failed);
+                }
+                agenda.push(Agenda::Return);
+                agenda.push(Agenda::Task(task.target.expect("reference wasn't resolved")));
+                self.ctx.state_mut().calls.push((self.scope.slot, task.index));
+                Step::Next
+            },
+            TaskType::Slot => {
+                // the slot's behaviour is decomposed on its own, it can't backtrack into ours
+                match self.decompose_slot(task, over_plan) {
+                    Succeeded => Step::Next,
+                    status => Step::Done(status),
+                }
+            },
+            TaskType::Pause if self.scope.slot.is_some() => Step::Done(Failed),
+            TaskType::Pause => {
+                // everything left on the agenda gets picked up when we resume
+                self.ctx.state_mut().paused = true;
+                let left = agenda.iter().rev().filter_map(|entry| match entry {
+                    Agenda::Task(task_index) => Some(*task_index),
+                    Agenda::Return => None,
+                });
+                self.ctx.state_mut().partial_queue.extend(left);
+                Step::Done(Partial)
+            },
+        }
+    }
+
+    // has the innermost selector try its next sub-task, in a transaction of its own.
+    // Failed once it's tried them all, when it's taken off the stack.
+    fn next_choice(&mut self, choices: &mut Vec<Choice>, agenda: &mut Vec<Agenda>) -> Step {
+        let choice = choices.last_mut().expect("no selector to choose");
+        let task = self.behaviour.get_task(choice.task);
+        let position = match choice.order.get(choice.next) {
+            Some(position) => *position,
+            None => {
+                choices.pop();
+                return Step::Done(DecompositionStatus::Failed);
+            },
+        };
+        choice.next += 1;
+        let utility = task.task_type != TaskType::Selector;
+        if !utility && !self.can_beat_last_record(position) {
+            self.trace_rejected(task);
+            choices.pop();
+            return Step::Done(DecompositionStatus::Rejected);
+        }
+        match utility {
+            true => self.record_utility_choice(position),
+            false => self.record_choice(position),
+        }
+        self.ctx.state_mut().begin_transaction();
+        agenda.clone_from(&choice.agenda);
+        agenda.push(Agenda::Task(task.sub_tasks[position]));
+        Step::Next
+    }
+
+    // undoes choices back to the innermost selector with a sub-task left to try
+    fn backtrack(&mut self, choices: &mut Vec<Choice>, agenda: &mut Vec<Agenda>, over_plan: &mut Plan) -> Step {
+        while let Some(choice) = choices.last() {
+            self.undo_choice(choice, over_plan);
+            match self.next_choice(choices, agenda) {
+                Step::Done(DecompositionStatus::Failed) => continue,
+                step => return step,
+            }
+        }
+        Step::Done(DecompositionStatus::Failed)
+    }
+
+    // puts everything back as it was before the selector chose
+    fn undo_choice(&mut self, choice: &Choice, over_plan: &mut Plan) {
+        let state = self.ctx.state_mut();
+        state.rollback_transaction();
+        state.record.truncate(choice.record_len);
+        state.pending_cooldowns.truncate(choice.cooldowns_len);
+        state.diverged = choice.diverged;
+        state.beaten = choice.beaten;
+        state.calls.clone_from(&choice.calls);
+        over_plan.truncate(choice.plan_len);
     }
 
     fn decompose_pause(&mut self, plan: &mut Plan) -> DecompositionStatus {
         use DecompositionStatus::*;
 
+        // resuming only knows about the planner's own behaviour
+        if self.scope.slot.is_some() {
+            return Failed;
+        }
+
         self.ctx.state_mut().paused = true;
         self.ctx.state_mut().partial_queue.push_back(self.calling_task);
         
         Partial
     }
 
+    fn decompose_reference(&mut self, task: &Task<C>, over_plan: &mut Plan) -> DecompositionStatus {
+        if !self.can_go_deeper(task) {
+            return DecompositionStatus::Failed;
+        }
+        let target = self.behaviour.get_task(task.target.expect("reference wasn't resolved"));
+        self.ctx.state_mut().calls.push((self.scope.slot, task.index));
+        let status = target.decompose(self.ctx, self.behaviour, over_plan, self.scope);
+        self.ctx.state_mut().calls.pop();
+        status
+    }
+
+    fn decompose_slot(&mut self, task: &Task<C>, over_plan: &mut Plan) -> DecompositionStatus {
+        // only the planner's own behaviour has its slots filled
+        let filling = match self.scope.slot {
+            None => self.scope.slots.get(&task.name),
+            Some(_) => None,
+        };
+        let filling = match filling {
+            Some(filling) => filling,
+            None => {
+                if self.ctx.state().trace.is_some() {
+                    let path = self.behaviour.path_of(task.index);
+                    let name = "slot is filled".to_owned();
+                    self.ctx.state_mut().trace(TraceEvent::Condition { path: path, name: name, valid: false });
+                }
+                return DecompositionStatus::Failed;
+            },
+        };
+        let scope = Scope {
+            slots: self.scope.slots,
+            slot: Some(task.index),
+        };
+        filling.get_task(0).decompose(self.ctx, filling, over_plan, scope)
+    }
+
+    // references can recurse, so there has to be a limit somewhere
+    fn can_go_deeper(&mut self, task: &Task<C>) -> bool {
+        if self.ctx.state().calls.len() < self.behaviour.max_depth {
+            return true;
+        }
+        if self.ctx.state().trace.is_some() {
+            let path = self.behaviour.path_of(task.index);
+            self.ctx.state_mut().trace(TraceEvent::DepthLimit { path: path });
+        }
+        false
+    }
+
     fn decompose_primitive(&mut self, task: &Task<C>, over_plan: &mut Plan) -> DecompositionStatus {
         use DecompositionStatus::*;
 
-        if !task.is_valid(self.ctx) {
+        if !self.is_valid(task) {
             return Failed
         }
 
-        task.apply_effects(self.ctx);
-        over_plan.push_back(task.index);
+        self.apply_effects(task);
+        over_plan.push_back(self.plan_step(task));
         Succeeded
     }
 
@@ -217,7 +841,7 @@ where
     // }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum TaskStatus {
     Continue,
     Success,
@@ -228,7 +852,7 @@ impl Default for TaskStatus {
     fn default() -> Self {TaskStatus::Failure}
 }
 
-#[derive(Eq, PartialEq,)]
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum DecompositionStatus {
     Succeeded,
     Partial,
@@ -240,6 +864,21 @@ impl Default for DecompositionStatus {
     fn default() -> Self {DecompositionStatus::Rejected}
 }
 
+/// How selectors choose between their sub-tasks when planning
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum DecompositionMode {
+    /// Commit to the first sub-task that decomposes. Cheap, but a selector nested in
+    /// a sequence won't get another go if a later task in that sequence fails.
+    FirstMatch,
+    /// Go back and try the next alternative of earlier selectors whenever the rest of
+    /// the plan can't be found. Gives up after max_steps tasks have been visited.
+    Backtracking { max_steps: usize },
+}
+
+impl Default for DecompositionMode {
+    fn default() -> Self {DecompositionMode::FirstMatch}
+}
+
 /// adds a block of behaviour to a behaviourbuilder
 pub trait TaskMacro<T>
 where
@@ -0,0 +1,108 @@
+use crate::htn::EffectType;
+use crate::task::{DecompositionStatus, TaskType};
+use std::fmt;
+
+/// One thing that happened while decomposing. Tasks are named by their path from
+/// the root of the behaviour.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TraceEvent {
+    Task { path: String, task_type: TaskType },
+    Condition { path: String, name: String, valid: bool },
+    Effect { path: String, name: String, effect_type: EffectType },
+    /// A selector gave up because it couldn't beat the running plan
+    Rejected { path: String },
+    /// A reference wasn't followed, it would have gone past the behaviour's max depth
+    DepthLimit { path: String },
+    /// A utility selector scored one of its sub-tasks
+    Score { path: String, name: String, score: f32 },
+    Finished(DecompositionStatus),
+}
+
+/// Everything a planner did during its last decomposition, in order. Turn it on
+/// with Planner::set_tracing.
+#[derive(Default, Clone, Debug)]
+pub struct DecompositionTrace {
+    events: Vec<TraceEvent>,
+}
+
+impl DecompositionTrace {
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub(crate) fn push(&mut self, event: TraceEvent) {
+        self.events.push(event);
+    }
+
+    /// How the decomposition ended, if it got that far
+    pub fn status(&self) -> Option<DecompositionStatus> {
+        self.events.iter().rev().find_map(|event| match event {
+            TraceEvent::Finished(status) => Some(*status),
+            _ => None,
+        })
+    }
+
+    /// (task path, condition name) for every condition that came back invalid
+    pub fn failed_conditions(&self) -> Vec<(&str, &str)> {
+        self.events.iter().filter_map(|event| match event {
+            TraceEvent::Condition { path, name, valid: false } => Some((path.as_str(), name.as_str())),
+            _ => None,
+        }).collect()
+    }
+
+    /// A short human readable account of why the decomposition ended the way it did
+    pub fn explain(&self) -> String {
+        use DecompositionStatus::*;
+
+        let status = match self.status() {
+            Some(status) => status,
+            None => return "Nothing has been decomposed yet.".to_owned(),
+        };
+        let mut explanation = match status {
+            Succeeded => "Found a plan.".to_owned(),
+            Partial => "Found a partial plan, waiting on a pause.".to_owned(),
+            Rejected => "Kept the running plan, nothing found could beat it.".to_owned(),
+            Failed => "No plan found.".to_owned(),
+        };
+        if status == Failed {
+            let failed = self.failed_conditions();
+            if failed.len() == 0 {
+                explanation.push_str("\n  No conditions failed - check for empty branches or a step limit.");
+            }
+            for event in self.events.iter() {
+                if let TraceEvent::DepthLimit { path } = event {
+                    explanation.push_str(&format!("\n  '{}' went past the depth limit", path));
+                }
+            }
+            for (path, name) in failed {
+                explanation.push_str(&format!("\n  '{}' failed condition '{}'", path, name));
+            }
+        }
+        explanation
+    }
+}
+
+impl fmt::Display for DecompositionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in self.events.iter() {
+            match event {
+                TraceEvent::Task { path, task_type } => writeln!(f, "visit {:?} '{}'", task_type, path)?,
+                TraceEvent::Condition { path, name, valid } => {
+                    writeln!(f, "  condition '{}' on '{}': {}", name, path, if *valid { "valid" } else { "INVALID" })?
+                },
+                TraceEvent::Effect { path, name, effect_type } => {
+                    writeln!(f, "  effect '{}' ({:?}) on '{}'", name, effect_type, path)?
+                },
+                TraceEvent::Rejected { path } => writeln!(f, "  '{}' can't beat the running plan", path)?,
+                TraceEvent::DepthLimit { path } => writeln!(f, "  '{}' is too deep to follow", path)?,
+                TraceEvent::Score { path, name, score } => writeln!(f, "  score '{}' on '{}': {}", name, path, score)?,
+                TraceEvent::Finished(status) => writeln!(f, "finished: {:?}", status)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,46 @@
-
 use bevy_htn::prelude::*;
+use bevy_htn::task::TaskMacro;
+
+static ALARM: Key<bool> = Key::new("alarm");
+static ARRIVED: Key<bool> = Key::new("arrived");
+static ASLEEP: Key<bool> = Key::new("asleep");
+static AT: Key<String> = Key::new("at");
+static ATE: Key<bool> = Key::new("ate");
+static AXE: Key<bool> = Key::new("axe");
+static BLOCKED: Key<bool> = Key::new("blocked");
+static BOOK: Key<bool> = Key::new("book");
+static BORED: Key<bool> = Key::new("bored");
+static CAN_SHOUT: Key<bool> = Key::new("can_shout");
+static CLAIMED: Key<bool> = Key::new("claimed");
+static ENEMY_AT: Key<i32> = Key::new("enemy_at");
+static ENERGY: Key<i32> = Key::new("energy");
+static FATIGUE: Key<f32> = Key::new("fatigue");
+static FLED: Key<bool> = Key::new("fled");
+static FULL_ROOM: Key<String> = Key::new("full_room");
+static HUNGER: Key<i32> = Key::new("hunger");
+static HUNGRY: Key<bool> = Key::new("hungry");
+static HURR: Key<bool> = Key::new("hurr");
+static IN_CLASS: Key<bool> = Key::new("in_class");
+static LECTURE_HALL: Key<String> = Key::new("lecture_hall");
+static LOG: Key<String> = Key::new("log");
+static NAHHH: Key<bool> = Key::new("nahhh");
+static NOTHING: Key<bool> = Key::new("nothing");
+static PLANNED: Key<bool> = Key::new("planned");
+static POLLUTION: Key<bool> = Key::new("pollution");
+static POLLUTION2: Key<bool> = Key::new("pollution2");
+static ROOM: Key<String> = Key::new("room");
+static SITS: Key<i32> = Key::new("sits");
+static SITTING: Key<bool> = Key::new("sitting");
+static STUDIED: Key<bool> = Key::new("studied");
+static STUDYING: Key<bool> = Key::new("studying");
+static TEST: Key<bool> = Key::new("test");
+static TIRED: Key<bool> = Key::new("tired");
+static URGENT: Key<bool> = Key::new("urgent");
+static VISITED: Key<String> = Key::new("visited");
+static WEATHER: Key<String> = Key::new("weather");
+static WOOD: Key<bool> = Key::new("wood");
+static WRONG: Key<bool> = Key::new("wrong");
+static YEH: Key<bool> = Key::new("yeh");
 
 #[test]
 fn basic_planning_works() {
@@ -7,15 +48,21 @@ fn basic_planning_works() {
     builder
         .sequence("sequence1")
             .sequence("subsequence1")
+                .primitive("primitive1")
+                    .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
+                .end()
             .end()
         .end();
-    let mut b = builder.build();
+    let mut b = builder.build().unwrap();
     let mut p = Planner::default();
     let mut ctx = BeingContext::default();
 
-    p.tick(&b, &mut ctx);
+    p.tick(&b, &mut ctx, 0.0);
 
-    // now assert what?
+    assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+    assert_eq!(p.last_status(), TaskStatus::Success);
+    assert_eq!(p.current_task(), None);
+    assert!(!p.has_plan());
 }
 
 #[test]
@@ -24,15 +71,29 @@ fn more_complex_planning_works() {
     builder
         .sequence("sequence1")
             .sequence("subsequence1")
+                .primitive("primitive1")
+                    .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
+                .end()
+                .primitive("primitive2")
+                    .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Continue)
+                .end()
             .end()
         .end();
-    let mut b = builder.build();
+    let mut b = builder.build().unwrap();
     let mut p = Planner::default();
     let mut ctx = BeingContext::default();
 
-    p.tick(&b, &mut ctx);
+    p.tick(&b, &mut ctx, 0.0);
+
+    assert_eq!(p.last_status(), TaskStatus::Success);
+    assert_eq!(p.plan_names(&b), vec!["primitive2"]);
+    assert_eq!(p.plan().front().map(|step| step.task), Some(3));
+    assert_eq!(b.task_path(3), Some("sequence1/subsequence1/primitive2".to_owned()));
 
-    // now assert what?
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.current_task_name(&b), Some("primitive2".to_owned()));
+    assert_eq!(p.last_status(), TaskStatus::Continue);
+    assert!(p.plan_names(&b).is_empty());
 }
 
 #[test]
@@ -46,7 +107,7 @@ fn selector_continues_after_failed_task() {
             .primitive("Base_False")
                 .condition("always_false", |ctx: &BeingContext| false)
                 .do_action("set_wrong_flag", |ctx: &mut BeingContext| {
-                    ctx.set("wrong", Bool(true));
+                    ctx.set(&WRONG, Bool(true));
                     return TaskStatus::Success;
                 })
             .end()
@@ -54,19 +115,19 @@ fn selector_continues_after_failed_task() {
         .selector("i should be chosen")
             .primitive("Base_Real")
                 .do_action("set_flag", |ctx: &mut BeingContext| {
-                    ctx.set("test", Bool(true));
+                    ctx.set(&TEST, Bool(true));
                     return TaskStatus::Success;
                 })
             .end()
         .end()
     .end();
-    let mut b = builder.build();
+    let mut b = builder.build().unwrap();
     let mut p = Planner::default();
     let mut ctx = BeingContext::default();
 
-    p.tick(&b, &mut ctx);
-    assert!(ctx.test_value("wrong", &Bool(true)).is_none());
-    assert_eq!(ctx.get("test").unwrap(), &Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(ctx.test_value(&WRONG, &Bool(true)).is_none());
+    assert_eq!(ctx.get(&TEST).unwrap(), &Bool(true));
 }
 
 
@@ -79,35 +140,35 @@ fn sequence_with_failed_task_gives_no_plan() {
     .sequence("i should give no plan")
         .primitive("I should run successfully")
             .do_action("durr", |ctx: &mut BeingContext| {
-                ctx.set("yeh", Bool(true));
+                ctx.set(&YEH, Bool(true));
                 TaskStatus::Success
             })
         .end()
         .primitive("I should also run")
             .do_action("hurr", |ctx: &mut BeingContext| {
-                ctx.set("yeh m8 lmfao", Bool(true));
+                ctx.set(&HURR, Bool(true));
                 TaskStatus::Success
             })
         .end()
         .primitive("I should fail")
             .condition("always invalid", |ctx: &BeingContext| {
-                ctx.test_value("nothing", &Bool(true)).is_some()
+                ctx.test_value(&NOTHING, &Bool(true)).is_some()
             })
             .do_action("nah", |ctx: &mut BeingContext| {
-                ctx.set("nahhh", Bool(true));
+                ctx.set(&NAHHH, Bool(true));
                 TaskStatus::Success
             })
         .end()
     .end();
-    let mut b = builder.build();
+    let mut b = builder.build().unwrap();
     let mut p = Planner::default();
     let mut ctx = BeingContext::default();
 
-    p.tick(&b, &mut ctx);
+    p.tick(&b, &mut ctx, 0.0);
     assert!(!p.has_plan());
-    assert!(ctx.get("yeh").is_none());
-    assert!(ctx.get("hurr").is_none());
-    assert!(ctx.get("nahhh").is_none());
+    assert!(ctx.get(&YEH).is_none());
+    assert!(ctx.get(&HURR).is_none());
+    assert!(ctx.get(&NAHHH).is_none());
 }
 
 #[test]
@@ -119,25 +180,1291 @@ fn failed_sequence_does_not_pollute_context() {
     .sequence("i should fail but not pollute")
         .selector("Some of mine run")
             .primitive("I should run successfully")
-                .effect("pollute", |ctx: &mut BeingContext| {
-                    ctx.set("pollution", Bool(true))
+                .effect("pollute", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                    ctx.set(&POLLUTION, Bool(true))
                 })
+                .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
             .end()
             .primitive("I should run successfully too")
-                .effect("pollute2", |ctx: &mut BeingContext| {
-                    ctx.set("pollution2", Bool(true))
+                .effect("pollute2", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                    ctx.set(&POLLUTION2, Bool(true))
                 })
+                .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
             .end()
         .end()
         .primitive("I should fail")
             .condition("always fails", |ctx: &BeingContext| false)
+            .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+    .end();
+    let mut b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(ctx.get(&POLLUTION).is_none());
+    assert!(ctx.get(&POLLUTION2).is_none());
+}
+
+#[test]
+fn failed_sequence_restores_overwritten_values() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .sequence("i overwrite then fail")
+            .primitive("Go to class")
+                .effect("in class", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                    ctx.set(&IN_CLASS, Bool(true));
+                    ctx.remove(&HUNGRY);
+                })
+                .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+            .primitive("I should fail")
+                .condition("always fails", |ctx: &BeingContext| false)
+                .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+        .end()
+        .primitive("Fallback")
+            .do_action("noop", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&IN_CLASS, Bool(false));
+    ctx.set(&HUNGRY, Bool(true));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&IN_CLASS), Some(&Bool(false)));
+    assert_eq!(ctx.get(&HUNGRY), Some(&Bool(true)));
+    assert!(!ctx.state().in_transaction());
+}
+
+#[test]
+fn comparison_conditions_pick_the_right_branch() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Eat")
+            .condition_greater("hunger above 50", &HUNGER, Int32(50))
+            .do_action("eat", |ctx: &mut BeingContext| {
+                ctx.set(&ATE, Bool(true));
+                TaskStatus::Success
+            })
+        .end()
+        .primitive("Study")
+            .condition_in_range("not too tired", &FATIGUE, Float(0.0), Float(0.8))
+            .do_action("study", |ctx: &mut BeingContext| {
+                ctx.set(&STUDIED, Bool(true));
+                TaskStatus::Success
+            })
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&HUNGER, Int32(20));
+    ctx.set(&FATIGUE, Float(0.3));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(ctx.get(&ATE).is_none());
+    assert_eq!(ctx.get(&STUDIED), Some(&Bool(true)));
+}
+
+#[test]
+fn replan_keeps_running_plan_unless_beaten() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Flee")
+            .condition_equal("alarm is ringing", &ALARM, Bool(true))
+            .do_action("flee", |ctx: &mut BeingContext| {
+                ctx.set(&FLED, Bool(true));
+                TaskStatus::Continue
+            })
+        .end()
+        .sequence("Study")
+            .primitive("Sit down")
+                .do_action("sit", |ctx: &mut BeingContext| {
+                    let sits = match ctx.get(&SITS) {
+                        Some(Int32(n)) => *n,
+                        _ => 0,
+                    };
+                    ctx.set(&SITS, Int32(sits + 1));
+                    TaskStatus::Success
+                })
+            .end()
+            .primitive("Read")
+                .do_action("read", |ctx: &mut BeingContext| TaskStatus::Continue)
+            .end()
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&ALARM, Bool(false));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&SITS), Some(&Int32(1)));
+
+    // same branch again - the running plan should be kept rather than restarted
+    ctx.state_mut().dirty = true;
+    p.tick(&b, &mut ctx, 0.0);
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&SITS), Some(&Int32(1)));
+    assert!(ctx.get(&FLED).is_none());
+
+    // a higher priority branch replaces it
+    ctx.set(&ALARM, Bool(true));
+    ctx.state_mut().dirty = true;
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&FLED), Some(&Bool(true)));
+}
+
+fn urgent_or_alarm(backtracking: bool) -> Behaviour<BeingContext> {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .sequence("Urgent")
+            .condition_equal("urgent", &URGENT, Bool(true))
+            .selector("respond")
+                .primitive("Shout")
+                    .condition_equal("can shout", &CAN_SHOUT, Bool(true))
+                    .do_action("shout", |ctx: &mut BeingContext| TaskStatus::Continue)
+                .end()
+                .primitive("Wave")
+                    .do_action("wave", |ctx: &mut BeingContext| TaskStatus::Continue)
+                .end()
+            .end()
+        .end()
+        .sequence("Alarm")
+            .selector("react")
+                .primitive("Run")
+                    .do_action("run", |ctx: &mut BeingContext| TaskStatus::Continue)
+                .end()
+                .primitive("Hide")
+                    .do_action("hide", |ctx: &mut BeingContext| TaskStatus::Continue)
+                .end()
+            .end()
+        .end()
+    .end();
+    if backtracking {
+        builder.backtracking(100);
+    }
+    builder.build().unwrap()
+}
+
+#[test]
+fn a_plan_beaten_early_wins_whatever_it_picks_later() {
+    use Variant::*;
+
+    for backtracking in [false, true] {
+        let b = urgent_or_alarm(backtracking);
+        let mut p = Planner::default();
+        let mut ctx = BeingContext::default();
+        ctx.set(&URGENT, Bool(false));
+        ctx.set(&CAN_SHOUT, Bool(false));
+
+        // running [1, 0], alarm then run
+        p.tick(&b, &mut ctx, 0.0);
+        assert_eq!(p.current_task_name(&b), Some("Run".to_owned()));
+
+        // [0, 1] beats it at the root, even though wave comes after run
+        ctx.set(&URGENT, Bool(true));
+        p.tick(&b, &mut ctx, 0.0);
+        assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+        assert_eq!(p.current_task_name(&b), Some("Wave".to_owned()));
+    }
+}
+
+#[test]
+fn only_watched_changes_cause_a_replan() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use Variant::*;
+
+    // counts decompositions, since Flee is checked first every time
+    let checks = Arc::new(AtomicUsize::new(0));
+    let counter = checks.clone();
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Flee")
+            .depends_on(&ALARM)
+            .condition("alarm is ringing", move |ctx: &BeingContext| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                ctx.test_value(&ALARM, &Bool(true)) == Some(true)
+            })
+            .do_action("flee", |ctx: &mut BeingContext| {
+                ctx.set(&FLED, Bool(true));
+                TaskStatus::Continue
+            })
+        .end()
+        .primitive("Study")
+            .do_action("study", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+        .primitive("Wander")
+            .condition_equal("bored", &BORED, Bool(true))
+            .do_action("wander", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&ALARM, Bool(false));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+    // nothing the plan was decided on
+    ctx.set(&BORED, Bool(true));
+    ctx.set(&WEATHER, Str("rainy".to_owned()));
+    ctx.set(&ALARM, Bool(false));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+    ctx.set(&ALARM, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(checks.load(Ordering::SeqCst) > 1);
+    assert_eq!(ctx.get(&FLED), Some(&Bool(true)));
+}
+
+fn log(ctx: &mut BeingContext, entry: &str) {
+    let mut log = match ctx.get(&LOG) {
+        Some(Variant::Str(log)) => log.clone(),
+        _ => String::new(),
+    };
+    log.push_str(entry);
+    log.push(' ');
+    ctx.set(&LOG, Variant::Str(log));
+}
+
+fn walk_operator() -> FnOperator<BeingContext> {
+    FnOperator::new(|ctx: &mut BeingContext| {
+        log(ctx, "update");
+        match ctx.get(&ARRIVED) {
+            Some(Variant::Bool(true)) => TaskStatus::Success,
+            _ => TaskStatus::Continue,
+        }
+    })
+    .on_start(|ctx: &mut BeingContext| log(ctx, "start"))
+    .on_stop(|ctx: &mut BeingContext, status| log(ctx, &format!("stop:{:?}", status)))
+    .on_interrupt(|ctx: &mut BeingContext| log(ctx, "interrupt"))
+}
+
+#[test]
+fn operator_hooks_follow_the_task_lifecycle() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Flee")
+            .condition_equal("alarm is ringing", &ALARM, Bool(true))
+            .do_action("flee", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+        .primitive("Walk")
+            .do_action("walk", walk_operator())
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&ALARM, Bool(false));
+
+    p.tick(&b, &mut ctx, 0.0);
+    p.tick(&b, &mut ctx, 0.0);
+    ctx.set(&ARRIVED, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Str("start update update update stop:Success ".to_owned())));
+
+    // interrupted by a higher priority branch
+    ctx.remove(&LOG);
+    ctx.remove(&ARRIVED);
+    p.tick(&b, &mut ctx, 0.0);
+    ctx.set(&ALARM, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Str("start update interrupt ".to_owned())));
+}
+
+#[test]
+fn failing_exec_condition_interrupts_and_replans() {
+    use Variant::*;
+
+    let path_is_clear = |ctx: &BeingContext| ctx.get(&BLOCKED) != Some(&Bool(true));
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Walk")
+            .condition("path is clear", path_is_clear)
+            .exec_condition("path is clear", path_is_clear)
+            .do_action("walk", walk_operator())
+        .end()
+        .primitive("Climb")
+            .do_action("climb", |ctx: &mut BeingContext| {
+                log(ctx, "climb");
+                TaskStatus::Continue
+            })
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    p.tick(&b, &mut ctx, 0.0);
+    ctx.set(&BLOCKED, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Str("start update interrupt ".to_owned())));
+    assert_eq!(p.current_task(), None);
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.current_task_name(&b), Some("Climb".to_owned()));
+    assert_eq!(ctx.get(&LOG), Some(&Str("start update interrupt climb ".to_owned())));
+}
+
+struct GoTo;
+
+impl TaskMacro<BeingContext> for GoTo {
+    fn build(&self, builder: &mut BehaviourBuilder<BeingContext>) {
+        builder
+        .primitive("GoTo")
+            .condition("not there yet", |ctx: &BeingContext| ctx.param("target") != ctx.get(&AT))
+            .effect("arrive", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                let target = ctx.param("target").cloned().unwrap();
+                ctx.set(&AT, target);
+            })
+            .do_action("walk", |ctx: &mut BeingContext| {
+                let target = ctx.param("target").cloned().unwrap();
+                let mut visited = match ctx.get(&VISITED) {
+                    Some(Variant::Str(visited)) => visited.clone(),
+                    _ => String::new(),
+                };
+                if let Variant::Str(room) = target {
+                    visited.push_str(&room);
+                    visited.push(' ');
+                }
+                ctx.set(&VISITED, Variant::Str(visited));
+                TaskStatus::Success
+            })
+        .end();
+    }
+}
+
+#[test]
+fn parameters_are_bound_at_planning_and_handed_to_operators() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .sequence("Day")
+        .sequence("Lecture")
+            .bind_key("target", &LECTURE_HALL)
+            .task_macro(GoTo)
+        .end()
+        .sequence("Lunch")
+            .bind_value("target", Str("canteen".to_owned()))
+            .task_macro(GoTo)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    // nothing to bind the lecture hall to yet
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(ctx.get(&VISITED).is_none());
+
+    ctx.set(&LECTURE_HALL, Str("hall B".to_owned()));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&VISITED), Some(&Str("hall B ".to_owned())));
+    // the running task's parameters are gone once it's done
+    assert!(ctx.param("target").is_none());
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&VISITED), Some(&Str("hall B canteen ".to_owned())));
+    assert_eq!(ctx.get(&AT), Some(&Str("canteen".to_owned())));
+}
+
+#[test]
+fn references_take_parameters_from_where_they_are_made() {
+    use Variant::*;
+
+    for backtracking in [false, true] {
+        let mut builder = BehaviourBuilder::new("test");
+        builder
+        .selector("root")
+            .sequence("Day")
+                .sequence("Lecture")
+                    .bind_key("target", &LECTURE_HALL)
+                    .reference("WalkThere")
+                .end()
+                .sequence("Lunch")
+                    .bind_value("target", Str("canteen".to_owned()))
+                    .reference("WalkThere")
+                .end()
+            .end()
+            .sequence("WalkThere")
+                .task_macro(GoTo)
+            .end()
+        .end();
+        if backtracking {
+            builder.backtracking(100);
+        }
+        let b = builder.build().unwrap();
+        let mut p = Planner::default();
+        let mut ctx = BeingContext::default();
+        ctx.set(&LECTURE_HALL, Str("hall B".to_owned()));
+
+        p.tick(&b, &mut ctx, 0.0);
+        p.tick(&b, &mut ctx, 0.0);
+        assert_eq!(ctx.get(&VISITED), Some(&Str("hall B canteen ".to_owned())));
+    }
+}
+
+#[test]
+fn a_moving_bound_key_replaces_the_plan_it_was_bound_for() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Flee")
+            .condition_equal("alarm is ringing", &ALARM, Bool(true))
+            .do_action("flee", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+        .primitive("Chase")
+            .bind_key("target", &ENEMY_AT)
+            .do_action("chase", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&ALARM, Bool(false));
+    ctx.set(&ENEMY_AT, Int32(1));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.param("target"), Some(&Int32(1)));
+
+    // the same branch, but not the same plan
+    ctx.set(&ENEMY_AT, Int32(2));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+    assert_eq!(p.current_task_name(&b), Some("Chase".to_owned()));
+    assert_eq!(ctx.param("target"), Some(&Int32(2)));
+}
+
+fn patrol_until_tired(max_depth: usize) -> Behaviour<BeingContext> {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .max_depth(max_depth)
+    .selector("PatrolUntilTired")
+        .sequence("KeepGoing")
+            .primitive("Patrol")
+                .condition_greater("has energy", &ENERGY, Variant::Int32(0))
+                .effect("tire", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                    if let Some(Variant::Int32(energy)) = ctx.get(&ENERGY).cloned() {
+                        ctx.set(&ENERGY, Variant::Int32(energy - 1));
+                    }
+                })
+                .do_action("patrol", |ctx: &mut BeingContext| {
+                    log(ctx, "patrol");
+                    TaskStatus::Success
+                })
+            .end()
+            .reference("PatrolUntilTired")
+        .end()
+        .primitive("Rest")
+            .do_action("rest", |ctx: &mut BeingContext| {
+                log(ctx, "rest");
+                TaskStatus::Continue
+            })
+        .end()
+    .end();
+    builder.build().unwrap()
+}
+
+#[test]
+fn references_can_recurse() {
+    let b = patrol_until_tired(32);
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&ENERGY, Variant::Int32(3));
+
+    for _ in 0..5 {
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("patrol patrol patrol rest rest ".to_owned())));
+}
+
+#[test]
+fn recursion_stops_at_the_depth_limit() {
+    let b = patrol_until_tired(2);
+    let mut p = Planner::default();
+    p.set_tracing(true);
+    let mut ctx = BeingContext::default();
+    ctx.set(&ENERGY, Variant::Int32(10));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(p.trace().unwrap().events().iter().any(|event| matches!(event, TraceEvent::DepthLimit { .. })));
+    // two references deep, the third patrol would need a third reference to follow it
+    p.tick(&b, &mut ctx, 0.0);
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("patrol patrol rest ".to_owned())));
+}
+
+fn three_rounds(backtracking: bool) -> Behaviour<BeingContext> {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .max_depth(2)
+    .selector("root")
+        .sequence("ThreeRounds")
+            .reference("Round")
+            .reference("Round")
+            .reference("Round")
+        .end()
+        .sequence("Round")
+            .primitive("Patrol")
+                .do_action("patrol", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+        .end()
+    .end();
+    if backtracking {
+        builder.backtracking(100);
+    }
+    builder.build().unwrap()
+}
+
+#[test]
+fn depth_counts_nesting_not_sibling_references() {
+    for backtracking in [false, true] {
+        let b = three_rounds(backtracking);
+        let mut p = Planner::default();
+        let mut ctx = BeingContext::default();
+
+        // one round done, two to go, none of them deeper than one reference
+        p.tick(&b, &mut ctx, 0.0);
+        assert_eq!(p.plan_names(&b), vec!["Patrol", "Patrol"]);
+    }
+}
+
+#[test]
+fn slots_are_filled_and_emptied_per_planner() {
+    use std::sync::Arc;
+
+    let mut builder = BehaviourBuilder::new("student");
+    builder
+    .selector("root")
+        .slot("Quest")
+        .primitive("Idle")
+            .do_action("idle", |ctx: &mut BeingContext| {
+                log(ctx, "idle");
+                TaskStatus::Continue
+            })
+        .end()
+    .end();
+    let student = builder.build().unwrap();
+    let mut builder = BehaviourBuilder::new("quest");
+    builder
+    .sequence("Quest")
+        .primitive("FetchBook")
+            .do_action("fetch", FnOperator::new(|ctx: &mut BeingContext| {
+                log(ctx, "fetch");
+                TaskStatus::Continue
+            })
+            .on_interrupt(|ctx: &mut BeingContext| log(ctx, "drop")))
+        .end()
+    .end();
+    let quest = Arc::new(builder.build().unwrap());
+
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    let mut other = Planner::default();
+    let mut other_ctx = BeingContext::default();
+
+    p.tick(&student, &mut ctx, 0.0);
+    p.fill_slot("Quest", quest.clone());
+    p.tick(&student, &mut ctx, 0.0);
+    other.tick(&student, &mut other_ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("idle fetch ".to_owned())));
+    assert_eq!(other_ctx.get(&LOG), Some(&Variant::Str("idle ".to_owned())));
+
+    // emptied while its task is running
+    p.empty_slot("Quest");
+    p.tick(&student, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("idle fetch drop idle ".to_owned())));
+    assert!(p.get_slot("Quest").is_none());
+}
+
+fn student_day() -> Behaviour<BeingContext> {
+    let mut builder = BehaviourBuilder::new("student");
+    builder
+    .utility_selector("root")
+        .primitive("Eat")
+            .score_curve("hunger", &HUNGER, Curve::Linear { from: 0.0, to: 100.0 })
+            .do_action("eat", |ctx: &mut BeingContext| { log(ctx, "eat"); TaskStatus::Continue })
+        .end()
+        .primitive("Sleep")
+            .score_curve("fatigue", &FATIGUE, Curve::Power { from: 0.0, to: 100.0, power: 2.0 })
+            .do_action("sleep", |ctx: &mut BeingContext| { log(ctx, "sleep"); TaskStatus::Continue })
+        .end()
+        .primitive("Study")
+            .score("always a bit", |ctx: &BeingContext| 0.3)
+            .do_action("study", |ctx: &mut BeingContext| { log(ctx, "study"); TaskStatus::Continue })
+        .end()
+    .end();
+    builder.build().unwrap()
+}
+
+#[test]
+fn utility_selector_picks_the_best_score() {
+    use Variant::*;
+
+    let b = student_day();
+    let mut p = Planner::default();
+    p.set_tracing(true);
+    let mut ctx = BeingContext::default();
+    ctx.set(&HUNGER, Int32(10));
+    ctx.set(&FATIGUE, Float(70.0));
+
+    // 0.1 to eat, 0.49 to sleep, 0.3 to study
+    p.tick(&b, &mut ctx, 0.0);
+    let scores: Vec<(&str, f32)> = p.trace().unwrap().events().iter().filter_map(|event| match event {
+        TraceEvent::Score { name, score, .. } => Some((name.as_str(), *score)),
+        _ => None,
+    }).collect();
+    assert_eq!(scores.len(), 3);
+    assert_eq!(scores[0], ("hunger", 0.1));
+
+    // hunger rising past that is a change the plan depends on, so it's replanned
+    ctx.set(&HUNGER, Int32(80));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Str("sleep eat ".to_owned())));
+}
+
+fn idle() -> Behaviour<BeingContext> {
+    let mut builder = BehaviourBuilder::new("idle");
+    builder
+    .random_selector("Idle")
+        .primitive("Wander")
+            .do_action("wander", |ctx: &mut BeingContext| { log(ctx, "wander"); TaskStatus::Success })
+        .end()
+        .primitive("Chat")
+            .weight(2.0)
+            .do_action("chat", |ctx: &mut BeingContext| { log(ctx, "chat"); TaskStatus::Success })
+        .end()
+        .primitive("Read")
+            .weight(0.0)
+            .do_action("read", |ctx: &mut BeingContext| { log(ctx, "read"); TaskStatus::Success })
+        .end()
+    .end();
+    builder.build().unwrap()
+}
+
+fn idle_log(seed: u64) -> String {
+    let b = idle();
+    let mut p = Planner::default();
+    p.set_seed(seed);
+    let mut ctx = BeingContext::default();
+    for _ in 0..20 {
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    match ctx.get(&LOG) {
+        Some(Variant::Str(log)) => log.clone(),
+        _ => String::new(),
+    }
+}
+
+#[test]
+fn random_selectors_repeat_themselves_with_the_same_seed() {
+    let log = idle_log(7);
+    assert_eq!(log, idle_log(7));
+    assert!(log.contains("wander") && log.contains("chat"));
+    // weight 0 only goes when nothing else can
+    assert!(!log.contains("read"));
+}
+
+#[test]
+fn waits_and_timeouts_go_by_planner_time() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .sequence("root")
+        .wait("Wait for the kettle", 1.0)
+        .primitive("Drink")
+            .timeout(2.0)
+            .do_action("drink", FnOperator::new(|ctx: &mut BeingContext| { log(ctx, "sip"); TaskStatus::Continue })
+                .on_stop(|ctx: &mut BeingContext, status| log(ctx, &format!("{:?}", status))))
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    p.tick(&b, &mut ctx, 0.5);
+    p.tick(&b, &mut ctx, 0.5);
+    assert_eq!(ctx.get(&LOG), None);
+    for _ in 0..4 {
+        p.tick(&b, &mut ctx, 1.0);
+    }
+    assert_eq!(ctx.get(&LOG), Some(&Str("sip sip Failure ".to_owned())));
+}
+
+#[test]
+fn cooldowns_hold_tasks_back_then_replan() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Shout")
+            .cooldown(2.0)
+            .do_action("shout", |ctx: &mut BeingContext| { log(ctx, "shout"); TaskStatus::Success })
+        .end()
+        .primitive("Wander")
+            .do_action("wander", |ctx: &mut BeingContext| { log(ctx, "wander"); TaskStatus::Continue })
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    for _ in 0..4 {
+        p.tick(&b, &mut ctx, 1.0);
+    }
+    // nothing changed, but the shout was ready again
+    assert_eq!(ctx.get(&LOG), Some(&Str("shout wander shout wander ".to_owned())));
+}
+
+#[test]
+fn repeats_plan_their_sub_tasks_again() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .repeat("Knock", 3)
+        .condition("awake", Not(|ctx: &BeingContext| ctx.get(&ASLEEP).is_some()))
+        .primitive("knock")
+            .do_action("knock", |ctx: &mut BeingContext| { log(ctx, "knock"); TaskStatus::Success })
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    assert_eq!(b.to_tree(), "Knock x3\n  knock\n");
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    for _ in 0..3 {
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    assert_eq!(ctx.get(&LOG), Some(&Str("knock knock knock ".to_owned())));
+
+    ctx.set(&ASLEEP, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Str("knock knock knock ".to_owned())));
+}
+
+#[derive(Default, Context)]
+struct StudentContext {
+    #[htn(state)]
+    state: ContextState,
+    #[htn(key = "energy")]
+    energy: i32,
+}
+
+#[test]
+fn derived_contexts_expose_their_key_fields() {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Nap")
+            .condition_less("tired", &ENERGY, Variant::Int32(5))
+            .do_action("nap", |ctx: &mut StudentContext| TaskStatus::Continue)
+        .end()
+        .primitive("Study")
+            .do_action("study", |ctx: &mut StudentContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = StudentContext::default();
+
+    ctx.energy = 10;
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.current_task_name(&b), Some("Study".to_owned()));
+
+    // the field changing is a change to the key, so the planner replans
+    ctx.energy = 2;
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&ENERGY), Some(&Variant::Int32(2)));
+    assert_eq!(p.current_task_name(&b), Some("Nap".to_owned()));
+}
+
+fn find_a_seat_then_study(backtracking: Option<usize>) -> Behaviour<BeingContext> {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    if let Some(max_steps) = backtracking {
+        builder.backtracking(max_steps);
+    }
+    builder
+    .sequence("Find a free seat then study")
+        .selector("Choose a room")
+            .primitive("Go to hall A")
+                .effect("in hall A", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&ROOM, Str("A".to_string())))
+                .do_action("go", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+            .primitive("Go to hall B")
+                .effect("in hall B", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&ROOM, Str("B".to_string())))
+                .do_action("go", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+        .end()
+        .primitive("Study")
+            .condition("room isn't full", |ctx: &BeingContext| {
+                ctx.test_value(&FULL_ROOM, ctx.get(&ROOM).unwrap()) == Some(false)
+            })
+            .do_action("study", |ctx: &mut BeingContext| {
+                ctx.set(&STUDYING, Bool(true));
+                TaskStatus::Success
+            })
+        .end()
+    .end();
+    builder.build().unwrap()
+}
+
+#[test]
+fn first_match_does_not_try_other_rooms() {
+    use Variant::*;
+
+    let b = find_a_seat_then_study(None);
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&FULL_ROOM, Str("A".to_string()));
+    ctx.set(&ROOM, Str("none".to_string()));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(!p.has_plan());
+    assert_eq!(ctx.get(&ROOM), Some(&Str("none".to_string())));
+}
+
+#[test]
+fn long_backtracking_plans_dont_run_out_of_stack() {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .backtracking(1_000_000)
+    .repeat("Patrols", 100_000)
+        .primitive("Patrol")
+            .do_action("patrol", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+    assert_eq!(p.plan().len(), 100_000 - 1);
+}
+
+#[test]
+fn backtracking_tries_the_next_room() {
+    use Variant::*;
+
+    let b = find_a_seat_then_study(Some(100));
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&FULL_ROOM, Str("A".to_string()));
+    ctx.set(&ROOM, Str("none".to_string()));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(p.has_plan());
+    assert_eq!(ctx.get(&ROOM), Some(&Str("B".to_string())));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&STUDYING), Some(&Bool(true)));
+}
+
+#[test]
+fn backtracking_gives_up_past_step_limit() {
+    use Variant::*;
+
+    let b = find_a_seat_then_study(Some(4));
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+    ctx.set(&FULL_ROOM, Str("A".to_string()));
+    ctx.set(&ROOM, Str("none".to_string()));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(!p.has_plan());
+    assert_eq!(ctx.get(&ROOM), Some(&Str("none".to_string())));
+}
+
+#[test]
+fn effects_apply_according_to_their_type() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .sequence("root")
+        .primitive("Claim a seat")
+            .effect("seat claimed", EffectType::Permanent, |ctx: &mut BeingContext| ctx.set(&CLAIMED, Bool(true)))
+            .effect("thinking about it", EffectType::PlanOnly, |ctx: &mut BeingContext| ctx.set(&PLANNED, Bool(true)))
+            .effect("sitting", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&SITTING, Bool(true)))
+            .do_action("sit", |ctx: &mut BeingContext| {
+                if ctx.get(&TIRED).is_some() {
+                    TaskStatus::Success
+                } else {
+                    TaskStatus::Continue
+                }
+            })
+        .end()
+        .primitive("Study")
+            .condition_equal("sat down", &SITTING, Bool(true))
+            .do_action("study", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    let mut ctx = BeingContext::default();
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert!(p.has_plan());
+    assert_eq!(ctx.get(&CLAIMED), Some(&Bool(true)));
+    assert!(ctx.get(&PLANNED).is_none());
+    assert!(ctx.get(&SITTING).is_none());
+
+    ctx.set(&TIRED, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&SITTING), Some(&Bool(true)));
+    assert!(ctx.get(&PLANNED).is_none());
+}
+
+#[test]
+fn trace_explains_why_there_is_no_plan() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("root")
+        .primitive("Eat")
+            .condition_greater("hungry", &HUNGER, Int32(50))
+            .do_action("eat", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+        .sequence("Study")
+            .primitive("Sit")
+                .effect("sitting", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&SITTING, Bool(true)))
+                .do_action("sit", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+            .primitive("Read")
+                .condition("has a book", |ctx: &BeingContext| ctx.get(&BOOK).is_some())
+                .do_action("read", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = Planner::default();
+    p.set_tracing(true);
+    let mut ctx = BeingContext::default();
+    ctx.set(&HUNGER, Int32(10));
+
+    p.tick(&b, &mut ctx, 0.0);
+    let trace = p.trace().unwrap();
+    assert_eq!(trace.status(), Some(DecompositionStatus::Failed));
+    assert_eq!(trace.failed_conditions(), vec![
+        ("root/Eat", "hungry"),
+        ("root/Study/Read", "has a book"),
+    ]);
+    assert!(trace.events().contains(&TraceEvent::Effect {
+        path: "root/Study/Sit".to_owned(),
+        name: "sitting".to_owned(),
+        effect_type: EffectType::PlanAndExecute,
+    }));
+    assert!(trace.explain().contains("'root/Study/Read' failed condition 'has a book'"));
+}
+
+fn wood_cutting() -> Behaviour<BeingContext> {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("actions")
+        .primitive("GatherSticks")
+            .cost(8.0)
+            .effect("wood", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&WOOD, Bool(true)))
+            .do_action("gather", |ctx: &mut BeingContext| { log(ctx, "gather"); TaskStatus::Success })
+        .end()
+        .primitive("ChopWood")
+            .cost(4.0)
+            .condition_equal("has axe", &AXE, Bool(true))
+            .effect("wood", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&WOOD, Bool(true)))
+            .do_action("chop", |ctx: &mut BeingContext| { log(ctx, "chop"); TaskStatus::Success })
+        .end()
+        .primitive("GetAxe")
+            .cost(2.0)
+            .condition("no axe", |ctx: &BeingContext| ctx.get(&AXE).is_none())
+            .effect("axe", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&AXE, Bool(true)))
+            .do_action("get axe", |ctx: &mut BeingContext| { log(ctx, "axe"); TaskStatus::Success })
+        .end()
+    .end();
+    builder.build().unwrap()
+}
+
+#[test]
+fn goap_finds_the_cheapest_plan_and_runs_it() {
+    let b = wood_cutting();
+    let mut p = GoapPlanner::default();
+    let mut ctx = BeingContext::default();
+    p.set_goal(&mut ctx, Goal::new("wood", |ctx: &BeingContext| ctx.get(&WOOD).is_some()));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.last_search(), Some(DecompositionStatus::Succeeded));
+    // the axe and chopping cost 6, gathering sticks 8
+    assert_eq!(p.planner().plan_names(&b), vec!["ChopWood"]);
+    // planning is done on the side, only running the tasks changes the context
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("axe ".to_owned())));
+
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("axe chop ".to_owned())));
+    assert_eq!(ctx.get(&WOOD), Some(&Variant::Bool(true)));
+
+    // the goal's met, so there's nothing left to do
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.nodes(), 1);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("axe chop ".to_owned())));
+}
+
+#[test]
+fn goap_only_searches_again_once_there_is_something_new() {
+    let b = wood_cutting();
+    let mut p = GoapPlanner::default();
+    let mut ctx = BeingContext::default();
+    p.set_goal(&mut ctx, Goal::new("wood", |ctx: &BeingContext| ctx.get(&WOOD).is_some()));
+
+    // one search for the plan, and one when it runs out to see the goal's met
+    for _ in 0..5 {
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    assert_eq!(p.searches(), 2);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("axe chop ".to_owned())));
+
+    ctx.remove(&WOOD);
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(p.searches(), 3);
+    assert_eq!(ctx.get(&LOG), Some(&Variant::Str("axe chop chop ".to_owned())));
+
+    // nor does a search that found nothing run every tick
+    ctx.remove(&WOOD);
+    p.set_goal(&mut ctx, Goal::new("never", |_: &BeingContext| false));
+    p.max_nodes = 5;
+    for _ in 0..3 {
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    assert_eq!(p.searches(), 4);
+    assert_eq!(p.last_search(), Some(DecompositionStatus::Failed));
+}
+
+#[test]
+fn goap_doesnt_look_again_at_states_it_found_a_cheaper_way_to() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .selector("actions")
+        .primitive("BuyBook")
+            .cost(5.0)
+            .effect("book", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&BOOK, Bool(true)))
+            .do_action("buy", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+        .primitive("GoToLibrary")
+            .cost(1.0)
+            .effect("visited", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&TIRED, Bool(true)))
+            .do_action("go", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+        .primitive("BorrowBook")
+            .cost(1.0)
+            .condition("at the library", |ctx: &BeingContext| ctx.get(&TIRED).is_some())
+            .effect("book", EffectType::PlanAndExecute, |ctx: &mut BeingContext| {
+                ctx.set(&BOOK, Bool(true));
+                ctx.remove(&TIRED);
+            })
+            .do_action("borrow", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+        .primitive("Study")
+            .cost(10.0)
+            .condition("has a book", |ctx: &BeingContext| ctx.get(&BOOK).is_some())
+            .effect("studied", EffectType::PlanAndExecute, |ctx: &mut BeingContext| ctx.set(&STUDIED, Bool(true)))
+            .do_action("study", |ctx: &mut BeingContext| TaskStatus::Success)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    let mut p = GoapPlanner::default();
+    let mut ctx = BeingContext::default();
+    p.set_goal(&mut ctx, Goal::new("studied", |ctx: &BeingContext| ctx.get(&STUDIED).is_some()));
+
+    let (plan, status) = p.find_plan(&b, &mut ctx);
+    assert_eq!(status, DecompositionStatus::Succeeded);
+    let paths: Vec<String> = plan.iter().filter_map(|step| b.task_path(step.task)).collect();
+    assert_eq!(paths, vec!["actions/GoToLibrary", "actions/BorrowBook", "actions/Study"]);
+    // the start, at the library, with a book, with a book at the library and studied -
+    // not having bought the book, which was queued before borrowing it was found
+    assert_eq!(p.nodes(), 5);
+}
+
+#[test]
+fn goap_gives_up_past_its_node_limit() {
+    let b = wood_cutting();
+    let mut p = GoapPlanner::new(2);
+    let mut ctx = BeingContext::default();
+    p.set_goal(&mut ctx, Goal::new("wood", |ctx: &BeingContext| ctx.get(&WOOD).is_some()));
+
+    // the cheapest states are the start and having the axe, then it runs out
+    let (plan, status) = p.find_plan(&b, &mut ctx);
+    assert_eq!(status, DecompositionStatus::Failed);
+    assert!(plan.is_empty());
+    assert_eq!(p.nodes(), 2);
+    assert_eq!(ctx.get(&AXE), None);
+
+    p.max_nodes = 3;
+    let (plan, status) = p.find_plan(&b, &mut ctx);
+    assert_eq!(status, DecompositionStatus::Succeeded);
+    assert_eq!(plan.len(), 2);
+}
+
+fn eat_or_sleep() -> Behaviour<BeingContext> {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .plan_cache(16)
+    .selector("root")
+        .primitive("Eat")
+            .condition_greater("hungry", &HUNGER, Variant::Int32(5))
+            .do_action("eat", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+        .primitive("Sleep")
+            .do_action("sleep", |ctx: &mut BeingContext| TaskStatus::Continue)
         .end()
     .end();
-    let mut b = builder.build();
+    builder.build().unwrap()
+}
+
+fn agent_with_hunger(hunger: i32) -> (Planner<BeingContext>, BeingContext) {
+    let mut ctx = BeingContext::default();
+    ctx.set(&HUNGER, Variant::Int32(hunger));
+    (Planner::default(), ctx)
+}
+
+#[test]
+fn plan_cache_is_shared_by_agents_in_the_same_state() {
+    let b = eat_or_sleep();
+    let (mut p1, mut ctx1) = agent_with_hunger(10);
+    let (mut p2, mut ctx2) = agent_with_hunger(10);
+    let (mut p3, mut ctx3) = agent_with_hunger(2);
+
+    p1.tick(&b, &mut ctx1, 0.0);
+    p2.tick(&b, &mut ctx2, 0.0);
+    assert_eq!(p1.current_task_name(&b), Some("Eat".to_owned()));
+    assert_eq!(p2.current_task_name(&b), Some("Eat".to_owned()));
+    // a different value of a key that was read is a different plan
+    p3.tick(&b, &mut ctx3, 0.0);
+    assert_eq!(p3.current_task_name(&b), Some("Sleep".to_owned()));
+
+    let stats = b.plan_cache().unwrap().stats();
+    assert_eq!(stats, CacheStats { hits: 1, misses: 2, entries: 2 });
+    assert!((stats.hit_rate() - 1.0 / 3.0).abs() < 0.001);
+
+    // what a cached plan was decided on is still watched, so a change replans (and
+    // as sleeping isn't better than eating, keeps eating)
+    ctx2.set(&HUNGER, Variant::Int32(2));
+    p2.tick(&b, &mut ctx2, 0.0);
+    assert_eq!(p2.last_decomposition(), Some(DecompositionStatus::Rejected));
+    assert_eq!(p2.current_task_name(&b), Some("Eat".to_owned()));
+}
+
+#[test]
+fn full_plan_cache_forgets_what_was_used_longest_ago() {
+    let mut b = eat_or_sleep();
+    b.set_plan_cache(2);
+    for hunger in [10, 2, 10, 3, 10] {
+        let (mut p, mut ctx) = agent_with_hunger(hunger);
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    // 2 was the one to go, as 10 had been used since
+    assert_eq!(b.plan_cache().unwrap().stats(), CacheStats { hits: 2, misses: 3, entries: 2 });
+    let (mut p, mut ctx) = agent_with_hunger(2);
+    p.tick(&b, &mut ctx, 0.0);
+    assert_eq!(b.plan_cache().unwrap().stats(), CacheStats { hits: 2, misses: 4, entries: 2 });
+}
+
+#[test]
+fn cached_plans_dont_wake_for_cooldowns_they_never_met() {
+    use Variant::*;
+
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .plan_cache(16)
+    .selector("root")
+        .sequence("Snack")
+            .condition_equal("hungry", &HUNGRY, Bool(true))
+            .primitive("Eat")
+                .cooldown(10.0)
+                .do_action("eat", |ctx: &mut BeingContext| TaskStatus::Success)
+            .end()
+        .end()
+        .primitive("Sleep")
+            .do_action("sleep", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+
+    // someone else not hungry leaves a plan to sleep in the cache
+    let mut other = Planner::default();
+    let mut other_ctx = BeingContext::default();
+    other_ctx.set(&HUNGRY, Bool(false));
+    other.tick(&b, &mut other_ctx, 0.0);
+
+    // eating starts its cooldown, so next time it's turned down until that's over
     let mut p = Planner::default();
     let mut ctx = BeingContext::default();
+    ctx.set(&HUNGRY, Bool(true));
+    p.tick(&b, &mut ctx, 0.0);
+    p.tick(&b, &mut ctx, 1.0);
+    assert_eq!(p.current_task_name(&b), Some("Sleep".to_owned()));
+
+    ctx.set(&HUNGRY, Bool(false));
+    p.reset(&mut ctx);
+    p.tick(&b, &mut ctx, 1.0);
+    assert_eq!(b.plan_cache().unwrap().stats().hits, 1);
+    assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+
+    // the cooldown the last decomposition met is nothing to do with this plan
+    p.tick(&b, &mut ctx, 10.0);
+    assert_eq!(p.last_decomposition(), Some(DecompositionStatus::Succeeded));
+    assert_eq!(p.current_task_name(&b), Some("Sleep".to_owned()));
+}
 
-    p.tick(&b, &mut ctx);
-    assert!(ctx.get("pollution").is_none());
-    assert!(ctx.get("pollution2").is_none());
+#[test]
+fn plan_cache_skips_random_decompositions() {
+    let mut builder = BehaviourBuilder::new("test");
+    builder
+    .plan_cache(16)
+    .random_selector("root")
+        .primitive("a")
+            .do_action("a", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+        .primitive("b")
+            .do_action("b", |ctx: &mut BeingContext| TaskStatus::Continue)
+        .end()
+    .end();
+    let b = builder.build().unwrap();
+    for _ in 0..3 {
+        let (mut p, mut ctx) = agent_with_hunger(0);
+        p.tick(&b, &mut ctx, 0.0);
+    }
+    assert_eq!(b.plan_cache().unwrap().stats(), CacheStats { hits: 0, misses: 3, entries: 0 });
 }
@@ -0,0 +1,171 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, TokenStream as TokenStream2, TokenTree};
+use quote::{quote, ToTokens};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, GenericParam, Lit, Member,
+    Meta, NestedMeta, Type,
+};
+
+/// Implements `bevy_htn::context::Context` by forwarding to the field marked
+/// `#[htn(state)]`, which must be a `ContextState`. e.g.
+///
+/// ```ignore
+/// #[derive(Component, TypeUuid, Default, Context)]
+/// #[uuid = "..."]
+/// pub struct StudentContext {
+///     #[htn(state)]
+///     state: ContextState,
+///     #[htn(key = "hunger")]
+///     hunger: i32,
+/// }
+/// ```
+///
+/// Fields marked `#[htn(key = "...")]` are copied into the state under that key at
+/// the start of every planner tick, so conditions can read them like any other key.
+/// Their types have to be one of the `KeyType`s, and can't use the struct's generic
+/// parameters, as each key is a static.
+#[proc_macro_derive(Context, attributes(htn))]
+pub fn derive_context(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// a field exposed as a blackboard key
+struct KeyField {
+    member: Member,
+    ty: Type,
+    key: String,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(input, "Context can only be derived for structs")),
+    };
+
+    let mut state: Option<Member> = None;
+    let mut keys: Vec<KeyField> = vec![];
+    for (index, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(index.into()),
+        };
+        for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("htn")) {
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                meta => return Err(syn::Error::new_spanned(meta, "expected #[htn(state)] or #[htn(key = \"...\")]")),
+            };
+            for nested in list.nested.iter() {
+                match nested {
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident("state") => {
+                        if state.is_some() {
+                            return Err(syn::Error::new_spanned(path, "only one field can be the #[htn(state)]"));
+                        }
+                        state = Some(member.clone());
+                    }
+                    NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("key") => {
+                        let key = match &pair.lit {
+                            Lit::Str(key) => key.value(),
+                            lit => return Err(syn::Error::new_spanned(lit, "the key should be a string")),
+                        };
+                        if let Some(param) = uses_generic(&field.ty, input) {
+                            return Err(syn::Error::new_spanned(
+                                &field.ty,
+                                format!("#[htn(key)] fields can't use the generic parameter `{}`, as their keys are statics", param),
+                            ));
+                        }
+                        keys.push(KeyField {
+                            member: member.clone(),
+                            ty: field.ty.clone(),
+                            key: key,
+                        });
+                    }
+                    other => return Err(syn::Error::new_spanned(other, "expected state or key = \"...\"")),
+                }
+            }
+        }
+    }
+
+    let state = match state {
+        Some(state) => state,
+        None => {
+            let span = match fields {
+                Fields::Unit => input.ident.span(),
+                fields => fields.span(),
+            };
+            return Err(syn::Error::new(span, "Context needs a ContextState field marked #[htn(state)]"));
+        }
+    };
+
+    // one static Key per field, so syncing never has to look a name up
+    let syncs = keys.iter().map(|KeyField { member, ty, key }| {
+        quote! {
+            {
+                static KEY: ::bevy_htn::key::Key<#ty> = ::bevy_htn::key::Key::new(#key);
+                let value = ::std::clone::Clone::clone(&self.#member);
+                self.#state.set_value(&KEY, value);
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::bevy_htn::context::Context for #name #ty_generics #where_clause {
+            fn state(&self) -> &::bevy_htn::context::ContextState {
+                &self.#state
+            }
+
+            fn state_mut(&mut self) -> &mut ::bevy_htn::context::ContextState {
+                &mut self.#state
+            }
+
+            fn add(&mut self, key: impl ::bevy_htn::key::AsKey, variant: ::bevy_htn::context::Variant) {
+                self.#state.add(key, variant)
+            }
+
+            fn set(&mut self, key: impl ::bevy_htn::key::AsKey, variant: ::bevy_htn::context::Variant) {
+                self.#state.set(key, variant)
+            }
+
+            fn get(&self, key: impl ::bevy_htn::key::AsKey) -> ::std::option::Option<&::bevy_htn::context::Variant> {
+                self.#state.get(key)
+            }
+
+            fn remove(&mut self, key: impl ::bevy_htn::key::AsKey) {
+                self.#state.remove(key)
+            }
+
+            fn test_value(&self, key: impl ::bevy_htn::key::AsKey, value: &::bevy_htn::context::Variant) -> ::std::option::Option<bool> {
+                self.#state.test_value(key, value)
+            }
+
+            fn sync_keys(&mut self) {
+                #(#syncs)*
+            }
+        }
+    })
+}
+
+// the first of the struct's type or const parameters the type mentions
+fn uses_generic(ty: &Type, input: &DeriveInput) -> Option<Ident> {
+    let params: Vec<&Ident> = input.generics.params.iter().filter_map(|param| match param {
+        GenericParam::Type(param) => Some(&param.ident),
+        GenericParam::Const(param) => Some(&param.ident),
+        GenericParam::Lifetime(_) => None,
+    }).collect();
+    if params.is_empty() {
+        return None;
+    }
+    fn find(tokens: TokenStream2, params: &[&Ident]) -> Option<Ident> {
+        tokens.into_iter().find_map(|token| match token {
+            TokenTree::Ident(ident) if params.iter().any(|param| **param == ident) => Some(ident),
+            TokenTree::Group(group) => find(group.stream(), params),
+            _ => None,
+        })
+    }
+    find(ty.to_token_stream(), &params)
+}
@@ -1,5 +1,5 @@
-use std::collections::HashMap;
 use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
 use bevy_htn::prelude::*;
 use rand::prelude::*;
 use crate::{
@@ -17,20 +17,26 @@ pub struct AiPlugin;
 impl Plugin for AiPlugin {
     fn build(&self, app: &mut App) {
         app
-        .insert_resource(BehaviourMap::default())
+        .add_plugin(HtnPlugin::<EnemyContext>::default())
+        .add_plugin(HtnAssetPlugin::<EnemyContext>::default())
         .add_startup_system(startup)
-        .add_system(ai_system)
-        .add_system(senses_system)
+        .add_system(ai_system.after(HtnSystem::Tick))
+        .add_sensor(Sensor::new(|transform: &Transform, ctx: &mut EnemyContext| {
+            let position = transform.translation.truncate();
+            ctx.get_store_mut().current_pos = position;
+            ctx.set_value(&POSITION, position);
+        }))
         ;
     }
 }
 
-#[derive(Component,)]
-struct PlannerComponent<C>(Planner<C>) where C: Context;
+/// Where the agent is, written by the Transform sensor
+pub static POSITION: Key<Vec2> = Key::new("position");
 
-#[derive(Component, Default,)]
+#[derive(Component, TypeUuid, Default, Context)]
+#[uuid = "3f0b8d71-c2a4-4e19-9d56-7a1e0c4b82f3"]
 pub struct EnemyContext {
-    pub name: String,
+    #[htn(state)]
     pub state: ContextState,
     pub actor_store: ActorStore,
 }
@@ -45,49 +51,41 @@ impl ActorContext for EnemyContext {
     }
 }
 
-#[derive(Default)]
-struct BehaviourMap {
-    pub behaviours: HashMap<String, Behaviour<EnemyContext>>,
-    // pub behaviours: HashMap<String, i32>,
-}
-
-impl Context for EnemyContext {
-    fn state(&self) -> &ContextState { &self.state }
-    fn state_mut(&mut self) -> &mut ContextState { &mut self.state }
-    fn add(&mut self, key: &str, variant: Variant) { self.state.add(key, variant) }
-    fn set(&mut self, key: &str, variant: Variant) { self.state.set(key, variant) }
-    fn get(&self, key: &str) -> Option<&Variant> { self.state.get(key) }
-    fn remove(&mut self, key: &str) { self.state.remove(key) }
-    fn test_value(&self, key: &str, value: &Variant) -> Option<bool> { self.state.test_value(key, value) }
-}
-
 fn startup(
-    mut behaviour_map: ResMut<BehaviourMap>,
+    mut behaviours: ResMut<BehaviourRegistry<EnemyContext>>,
+    library: Res<BehaviourLibrary<EnemyContext>>,
 ) {
+    // so .htn files can use it too
+    library.add_macro("MoveRandomly", MoveRandomly);
+
     let mut builder: BehaviourBuilder<EnemyContext> = BehaviourBuilder::new("Enemy");
     builder
         .selector("BeEnemy")
             .task_macro(MoveRandomly)
         .end()
         ;
-    let behaviour = builder.build();
+    let behaviour = match builder.build() {
+        Ok(behaviour) => behaviour,
+        Err(e) => {
+            error!("Couldn't build the enemy behaviour: {}", e);
+            return;
+        }
+    };
     behaviour.print();
-    let mut planner = Planner::default();
-    let mut ctx = EnemyContext::default();
-    planner.tick(&behaviour, &mut ctx);
-    behaviour_map.behaviours.insert("BeEnemy".to_string(), behaviour);
+    behaviours.insert(behaviour);
 }
 
+// planners are ticked by the HtnPlugin, this just acts on what they asked for
 fn ai_system(
-    assets: ResMut<Assets<ColorMaterial>>,
-    behaviour_map: Res<BehaviourMap>,
     q_navmesh: Query<&NavMesh>,
-    mut q_ai: Query<(&mut EnemyContext, &mut PlannerComponent<EnemyContext>, &mut NavAgent)>,
+    mut q_ai: Query<(&mut EnemyContext, &mut NavAgent)>,
 ) {
-    for (mut ctx, mut planner, mut nav) in q_ai.iter_mut() {
-        let behaviour = behaviour_map.behaviours.get(&ctx.name).unwrap();
-        planner.0.tick(behaviour, &mut *ctx);
+    for (mut ctx, mut nav) in q_ai.iter_mut() {
         let store = ctx.get_store_mut();
+        if store.cancel_move {
+            nav.path = None;
+            store.cancel_move = false;
+        }
         if store.wants_new_location {
             let navmesh = q_navmesh.get_single().expect("There should be exactly 1 navmesh");
             let curr = store.current_pos;
@@ -104,17 +102,6 @@ fn ai_system(
     }
 }
 
-fn senses_system(
-    time: Res<Time>,
-    mut q_context: Query<(Entity, &mut EnemyContext, &Transform)>,
-) {
-    for (entity, mut ctx, transform) in q_context.iter_mut() {
-        let store = ctx.get_store_mut();
-        store.current_time += time.delta_seconds();
-        store.current_pos = transform.translation.truncate();
-    }
-}
-
 const MAX_MOVE_DISTANCE: f32 = 700.0;
 const MOVE_TIMEOUT: f32 = 4.0;
 const SIGHT_RADIUS: f32 = 400.0;
@@ -1,6 +1,6 @@
 use bevy_htn::{
     context::Context,
-    prelude::{BehaviourBuilder, TaskStatus},
+    prelude::{BehaviourBuilder, FnOperator, TaskStatus},
     task::TaskMacro,
 };
 use bevy::prelude::*;
@@ -10,7 +10,8 @@ pub struct ActorStore {
     pub move_target: Option<Vec2>,
     pub current_pos: Vec2,
     pub wants_new_location: bool,
-    pub current_time: f32,
+    // set when a move is interrupted, so the nav agent's path gets dropped
+    pub cancel_move: bool,
 }
 
 pub trait ActorContext: Context {
@@ -26,20 +27,28 @@ where
 {
     fn build(&self, builder: &mut BehaviourBuilder<T>) {
         builder
-        .primitive("MoveRandomly")
-            .condition("The timer has expired", |ctx: &T| ctx.get_store().current_time > 4.0)
-            .do_action("Choose new location", |ctx: &mut T| -> TaskStatus {
-                let store = ctx.get_store_mut();
-                if let Some(target) = store.move_target {
-                    if target.abs_diff_eq(store.current_pos, f32::EPSILON) {
-                        store.wants_new_location = true;
-                        store.current_time = 0.0;
-                        return TaskStatus::Success;
+        .sequence("MoveRandomly")
+            .wait("Linger", 4.0)
+            .primitive("Go somewhere")
+                .do_action("Choose new location", FnOperator::new(|ctx: &mut T| -> TaskStatus {
+                    let store = ctx.get_store();
+                    match store.move_target {
+                        Some(target) if target.abs_diff_eq(store.current_pos, f32::EPSILON) => TaskStatus::Success,
+                        _ => TaskStatus::Continue,
                     }
-                }
-                store.wants_new_location = false;
-                TaskStatus::Continue
-            })
+                })
+                .on_start(|ctx: &mut T| {
+                    let store = ctx.get_store_mut();
+                    store.move_target = None;
+                    store.wants_new_location = true;
+                })
+                .on_interrupt(|ctx: &mut T| {
+                    let store = ctx.get_store_mut();
+                    store.move_target = None;
+                    store.wants_new_location = false;
+                    store.cancel_move = true;
+                }))
+            .end()
         .end();
     }
 }
\ No newline at end of file
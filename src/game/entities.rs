@@ -64,19 +64,16 @@ pub fn spawn_standard_boi(
         
     if is_enemy {
         entity.insert(Enemy)
+            .insert(BehaviourName("Enemy".to_string()))
             .insert(EnemyContext {
-                name: "BeEnemy".to_string(),
                 state: ContextState::default(),
                 actor_store: ActorStore {
                     move_target: None,
                     current_pos: pos,
                     wants_new_location: true,
-                    current_time: 0.0,
+                    cancel_move: false,
                 }
-            })
-            // TODO UPDATE
-            // .insert(Planner::<EnemyContext>::default())
-            ;
+            });
     }
 }
 